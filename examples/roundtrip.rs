@@ -0,0 +1,28 @@
+//! Read a bytecode file, strip its debug information, and write it to a new
+//! file.
+//!
+//! Usage: `roundtrip <input> <output>`
+
+extern crate lua_kit;
+
+use std::{env, process};
+
+fn main() {
+	let args: Vec<String> = env::args().collect();
+	if args.len() != 3 {
+		eprintln!("usage: roundtrip <input> <output>");
+		process::exit(1);
+	}
+	let mut chunk = match lua_kit::read_chunk_from_path(&args[1]) {
+		Ok(chunk) => chunk,
+		Err(err) => {
+			eprintln!("{}: {}", args[1], err);
+			process::exit(1);
+		}
+	};
+	chunk.strip_debug();
+	if let Err(err) = lua_kit::write_chunk_to_path(&args[2], &chunk) {
+		eprintln!("{}: {}", args[2], err);
+		process::exit(1);
+	}
+}
@@ -0,0 +1,33 @@
+//! Strip the debug information from every bytecode file in a directory.
+//!
+//! Usage: `strip <directory>`
+
+extern crate lua_kit;
+
+use std::{env, fs, process};
+use std::io::{Read, Write};
+
+fn main() {
+	let dir = match env::args().nth(1) {
+		Some(dir) => dir,
+		None => {
+			println!("usage: strip <directory>");
+			process::exit(1);
+		}
+	};
+	for entry in fs::read_dir(&dir).unwrap() {
+		let path = entry.unwrap().path();
+		if !path.is_file() {
+			continue;
+		}
+		let mut input = Vec::new();
+		fs::File::open(&path).unwrap().read_to_end(&mut input).unwrap();
+		match lua_kit::process_chunk(&input, |chunk| chunk.strip_debug()) {
+			Ok(output) => {
+				fs::File::create(&path).unwrap().write_all(&output).unwrap();
+				println!("{}: {} -> {} bytes", path.display(), input.len(), output.len());
+			}
+			Err(err) => println!("{}: {}", path.display(), err),
+		}
+	}
+}
@@ -0,0 +1,483 @@
+//! Control flow and data analysis of function code.
+
+use std::cmp;
+use std::collections::BTreeSet;
+
+use bytecode::{self, ArgMode, DecodedInstruction, Opcode, RK};
+use super::{Constant, Function, Instruction, LuaString, Upvalue};
+
+/// A run of instructions which is only entered at its start and only left
+/// at its end.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BasicBlock {
+	/// The program counter of the first instruction in the block.
+	pub start: usize,
+	/// The program counter after the last instruction in the block.
+	pub end: usize,
+	/// The indices of the blocks control may pass to from this one.
+	pub successors: Vec<usize>,
+}
+
+/// A constant along with the instructions which refer to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConstantUsage {
+	/// The index of the constant in the pool.
+	pub index: usize,
+	/// The constant itself.
+	pub constant: Constant,
+	/// The program counters of the instructions referring to the constant.
+	pub pcs: Vec<usize>,
+}
+
+/// The registers an instruction reads and writes.
+///
+/// Ranges whose length is only known at runtime (a `B` or `C` of zero
+/// meaning "up to the top") include only their first register.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RegEffects {
+	/// The registers whose values the instruction uses.
+	pub reads: Vec<u32>,
+	/// The registers the instruction assigns to.
+	pub writes: Vec<u32>,
+}
+
+/// A register or upvalue of a function captured by one of its nested
+/// functions, as found by `Function::captured_upvalues`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UpvalueUse {
+	/// The index of the nested function which captures it.
+	pub proto: usize,
+	/// The index of the upvalue in the nested function.
+	pub index: usize,
+	/// What is captured: `Stack` for a register of this function, which
+	/// then escapes into the closure, or `Outer` for one of its upvalues.
+	pub captured: Upvalue,
+	/// The upvalue's name in the nested function, if it has debug info.
+	pub name: Option<LuaString>,
+}
+
+/// The registers live around each instruction of a function, as computed
+/// by `Function::register_liveness`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Liveness {
+	/// The registers live before each instruction, in ascending order.
+	pub live_in: Vec<Vec<u32>>,
+	/// The registers live after each instruction, in ascending order.
+	pub live_out: Vec<Vec<u32>>,
+}
+
+impl Liveness {
+	/// Whether `reg` is live before the instruction at `pc`.
+	pub fn is_live_at(&self, pc: usize, reg: u32) -> bool {
+		match self.live_in.get(pc) {
+			Some(live) => live.binary_search(&reg).is_ok(),
+			None => false,
+		}
+	}
+}
+
+/// Whether control may continue to the next instruction after this one.
+///
+/// `LoadBool` with a nonzero `C` always skips the next instruction, so it
+/// never falls through.
+pub fn falls_through(ins: &DecodedInstruction) -> bool {
+	match *ins {
+		DecodedInstruction::ABC(Opcode::LoadBool, _, _, c) => c == 0,
+		_ => !matches!(ins.opcode(), Opcode::Jump | Opcode::ForPrep | Opcode::Return),
+	}
+}
+
+/// The highest register an instruction refers to, if any.
+///
+/// Ranges whose length is only known at runtime (a `B` or `C` of zero
+/// meaning "up to the top") contribute only their first register.
+fn max_register(ins: &DecodedInstruction) -> Option<u32> {
+	let (op, a, b, c) = match *ins {
+		DecodedInstruction::ABC(op, a, b, c) => (op, a as u32, b, c),
+		DecodedInstruction::ABx(_, a, _) => return Some(a as u32),
+		DecodedInstruction::AsBx(Opcode::Jump, a, _) => return if a > 0 { Some(a as u32 - 1) } else { None },
+		DecodedInstruction::AsBx(Opcode::TForLoop, a, _) => return Some(a as u32 + 1),
+		DecodedInstruction::AsBx(_, a, _) => return Some(a as u32 + 3),
+		DecodedInstruction::Ax(..) => return None,
+	};
+	let mut max = match op {
+		Opcode::SetTabUp | Opcode::Eq | Opcode::Less | Opcode::LessEq => None,
+		_ => Some(a),
+	};
+	{
+		let mut include = |reg: u32| max = Some(max.map_or(reg, |m| cmp::max(m, reg)));
+		let (b_mode, c_mode) = op.arg_modes();
+		for &(mode, value) in &[(b_mode, b), (c_mode, c)] {
+			match mode {
+				ArgMode::Register => include(value),
+				ArgMode::Constant => if let RK::R(r) = RK::decode(value) { include(r as u32) },
+				_ => {}
+			}
+		}
+		match op {
+			Opcode::LoadNil | Opcode::SetList => include(a + b),
+			Opcode::Self_ => include(a + 1),
+			Opcode::Call => {
+				if b > 0 { include(a + b - 1) }
+				if c > 1 { include(a + c - 2) }
+			}
+			Opcode::TailCall if b > 0 => include(a + b - 1),
+			Opcode::Return | Opcode::VarArg if b > 1 => include(a + b - 2),
+			Opcode::TForCall => include(a + 2 + c),
+			_ => {}
+		}
+	}
+	max
+}
+
+/// The registers `first` to `first + len - 1`, or just `first` if `len`
+/// is not known.
+fn reg_range(first: u32, len: Option<u32>) -> Vec<u32> {
+	match len {
+		Some(len) => (first..first + len).collect(),
+		None => vec![first],
+	}
+}
+
+impl DecodedInstruction {
+	/// The program counter a jump or loop instruction at `pc` targets, which
+	/// is `sBx` after the next instruction.
+	///
+	/// Returns `None` for instructions without an `sBx` offset, or if the
+	/// target would be negative.
+	pub fn jump_target(&self, pc: usize) -> Option<usize> {
+		match *self {
+			DecodedInstruction::AsBx(_, _, sbx) => {
+				let target = pc as isize + 1 + sbx as isize;
+				if target < 0 { None } else { Some(target as usize) }
+			}
+			_ => None,
+		}
+	}
+
+	/// Find which registers the instruction reads and writes.
+	///
+	/// `RK` parameters count as reads only when they name a register.
+	/// Upvalues, constants, and the registers a `Closure` captures are not
+	/// included, nor is the closing of upvalues by `Jump`.
+	pub fn reg_effects(&self) -> RegEffects {
+		let (op, a, b, c) = match *self {
+			DecodedInstruction::ABC(op, a, b, c) => (op, a as u32, b, c),
+			// LoadK, LoadKX, and Closure.
+			DecodedInstruction::ABx(_, a, _) => return RegEffects {
+				reads: vec![],
+				writes: vec![a as u32],
+			},
+			DecodedInstruction::AsBx(op, a, _) => {
+				let a = a as u32;
+				let (reads, writes) = match op {
+					Opcode::ForLoop => (vec![a, a + 1, a + 2], vec![a, a + 3]),
+					Opcode::ForPrep => (vec![a, a + 1, a + 2], vec![a, a + 1, a + 2]),
+					Opcode::TForLoop => (vec![a + 1], vec![a]),
+					_ => (vec![], vec![]),
+				};
+				return RegEffects {
+					reads,
+					writes,
+				};
+			}
+			DecodedInstruction::Ax(..) => return RegEffects::default(),
+		};
+		let (mut reads, writes) = match op {
+			Opcode::Move | Opcode::GetTable | Opcode::UnMinus | Opcode::BinNot | Opcode::Not |
+			Opcode::Len | Opcode::TestSet => (vec![b], vec![a]),
+			Opcode::Self_ => (vec![b], vec![a, a + 1]),
+			Opcode::LoadBool | Opcode::GetUpval | Opcode::GetTabUp | Opcode::NewTable |
+			Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Mod | Opcode::Pow | Opcode::Div |
+			Opcode::IntDiv | Opcode::BinAnd | Opcode::BinOr | Opcode::BinXor | Opcode::ShLeft |
+			Opcode::ShRight => (vec![], vec![a]),
+			Opcode::LoadNil => (vec![], reg_range(a, Some(b + 1))),
+			Opcode::SetUpval | Opcode::SetTable | Opcode::Test => (vec![a], vec![]),
+			Opcode::Concat => (reg_range(b, Some((c + 1).saturating_sub(b))), vec![a]),
+			Opcode::Call => (
+				reg_range(a, if b == 0 { None } else { Some(b) }),
+				match c { 0 => vec![a], _ => reg_range(a, Some(c - 1)) },
+			),
+			Opcode::TailCall => (reg_range(a, if b == 0 { None } else { Some(b) }), vec![]),
+			Opcode::Return => (match b { 0 => vec![a], _ => reg_range(a, Some(b - 1)) }, vec![]),
+			Opcode::TForCall => (vec![a, a + 1, a + 2], reg_range(a + 3, Some(c))),
+			Opcode::SetList => (reg_range(a, if b == 0 { None } else { Some(b + 1) }), vec![]),
+			Opcode::VarArg => (vec![], match b { 0 => vec![a], _ => reg_range(a, Some(b - 1)) }),
+			_ => (vec![], vec![]),
+		};
+		let (b_mode, c_mode) = op.arg_modes();
+		for &(mode, value) in &[(b_mode, b), (c_mode, c)] {
+			if mode == ArgMode::Constant {
+				if let RK::R(r) = RK::decode(value) {
+					reads.push(r as u32);
+				}
+			}
+		}
+		RegEffects {
+			reads,
+			writes,
+		}
+	}
+}
+
+/// The constants an instruction refers to.
+///
+/// `next` is the following instruction, which holds the index for `LoadKX`.
+pub fn constant_refs(ins: &DecodedInstruction, next: Option<Instruction>) -> Vec<usize> {
+	match *ins {
+		DecodedInstruction::ABx(Opcode::LoadK, _, bx) => vec![bx as usize],
+		DecodedInstruction::ABx(Opcode::LoadKX, ..) => match next.and_then(bytecode::decode) {
+			Some(DecodedInstruction::Ax(Opcode::ExtraArg, ax)) => vec![ax as usize],
+			_ => vec![],
+		},
+		DecodedInstruction::ABC(op, _, b, c) => {
+			let (b_mode, c_mode) = op.arg_modes();
+			let mut refs = Vec::new();
+			for &(mode, value) in &[(b_mode, b), (c_mode, c)] {
+				if let (ArgMode::Constant, RK::K(k)) = (mode, RK::decode(value)) {
+					refs.push(k as usize);
+				}
+			}
+			refs
+		}
+		_ => vec![],
+	}
+}
+
+/// The program counter an instruction may branch to, other than the next.
+fn branch_target(pc: usize, ins: &DecodedInstruction) -> Option<usize> {
+	match *ins {
+		DecodedInstruction::AsBx(..) => ins.jump_target(pc),
+		DecodedInstruction::ABC(Opcode::LoadBool, _, _, c) if c != 0 => Some(pc + 2),
+		DecodedInstruction::ABC(op, ..) if op.is_test() => Some(pc + 2),
+		_ => None,
+	}
+}
+
+impl Function {
+	/// Find every branch in the function's code.
+	///
+	/// Each entry is `(from_pc, to_pc)`. Jumps and loops target the
+	/// instruction `sBx` after the next, and tests and `LoadBool` with
+	/// nonzero `C` target the instruction after the one they skip.
+	/// Falling through to the next instruction is not included.
+	pub fn jump_targets(&self) -> Vec<(usize, usize)> {
+		self.decoded_instructions().filter_map(|(pc, ins)| {
+			ins.and_then(|ins| branch_target(pc, &ins)).map(|to| (pc, to))
+		}).collect()
+	}
+
+	/// Find every `Closure` instruction along with the nested function it
+	/// creates.
+	///
+	/// Each entry is `(pc, proto_index)`. Nested functions which appear in
+	/// no entry are never instantiated.
+	pub fn closure_edges(&self) -> Vec<(usize, usize)> {
+		self.decoded_instructions().filter_map(|(pc, ins)| match ins {
+			Some(DecodedInstruction::ABx(Opcode::Closure, _, bx)) => Some((pc, bx as usize)),
+			_ => None,
+		}).collect()
+	}
+
+	/// Find the registers and upvalues of this function which its nested
+	/// functions capture, from their upvalue descriptors.
+	///
+	/// Entries are ordered by nested function and then by upvalue index.
+	/// Registers appearing as `Stack` entries are the locals which escape
+	/// into closures.
+	pub fn captured_upvalues(&self) -> Vec<UpvalueUse> {
+		let mut uses = Vec::new();
+		for (proto, function) in self.protos.iter().enumerate() {
+			for (index, &captured) in function.upvalues.iter().enumerate() {
+				uses.push(UpvalueUse {
+					proto,
+					index,
+					captured,
+					name: function.debug.upvalues.get(index).cloned(),
+				});
+			}
+		}
+		uses
+	}
+
+	/// Partition the function's code into basic blocks.
+	///
+	/// Blocks are returned in program order. Branches to program counters
+	/// outside the code are ignored.
+	pub fn basic_blocks(&self) -> Vec<BasicBlock> {
+		let len = self.code.len();
+		let mut leader = vec![false; len + 1];
+		leader[0] = true;
+		for (pc, ins) in self.decoded_instructions() {
+			if let Some(ins) = ins {
+				if let Some(to) = branch_target(pc, &ins) {
+					if to < len {
+						leader[to] = true;
+					}
+					leader[pc + 1] = true;
+				} else if !falls_through(&ins) {
+					leader[pc + 1] = true;
+				}
+			}
+		}
+		leader[len] = true;
+
+		// Map each program counter to the block that starts there.
+		let mut block_at = vec![0; len + 1];
+		let mut starts = Vec::new();
+		for pc in 0..len {
+			if leader[pc] {
+				starts.push(pc);
+			}
+			block_at[pc] = starts.len() - 1;
+		}
+
+		starts.iter().enumerate().map(|(i, &start)| {
+			let end = starts.get(i + 1).cloned().unwrap_or(len);
+			let mut successors = Vec::new();
+			if let Some(ins) = bytecode::decode(self.code[end - 1]) {
+				if falls_through(&ins) && end < len {
+					successors.push(block_at[end]);
+				}
+				if let Some(to) = branch_target(end - 1, &ins) {
+					if to < len && !successors.contains(&block_at[to]) {
+						successors.push(block_at[to]);
+					}
+				}
+			} else if end < len {
+				successors.push(block_at[end]);
+			}
+			BasicBlock {
+				start,
+				end,
+				successors,
+			}
+		}).collect()
+	}
+
+	/// Find which instructions refer to each constant.
+	///
+	/// There is an entry for every constant in the pool, in order, and
+	/// constants with no referring instructions are unused. `RK` parameters
+	/// count only when they name a constant, and the index of a `LoadKX` is
+	/// read from its `ExtraArg`. Nested functions are not included.
+	pub fn constant_usage(&self) -> Vec<ConstantUsage> {
+		let mut usage: Vec<_> = self.constants.iter().enumerate().map(|(index, constant)| ConstantUsage {
+			index,
+			constant: constant.clone(),
+			pcs: Vec::new(),
+		}).collect();
+		for (pc, ins) in self.decoded_instructions() {
+			if let Some(ins) = ins {
+				for k in constant_refs(&ins, self.code.get(pc + 1).cloned()) {
+					if let Some(entry) = usage.get_mut(k) {
+						if !entry.pcs.contains(&pc) {
+							entry.pcs.push(pc);
+						}
+					}
+				}
+			}
+		}
+		usage
+	}
+
+	/// Find which registers are live before and after each instruction: that
+	/// is, which may be read before they are next written.
+	///
+	/// This is a backward dataflow over every path through the code, using
+	/// `DecodedInstruction::reg_effects`, so ranges running to the top of
+	/// the stack contribute only their first register. Writes which only
+	/// happen on some paths, such as `TestSet`'s, don't end a register's
+	/// liveness, and a `Closure` reads the registers it captures.
+	pub fn register_liveness(&self) -> Liveness {
+		let len = self.code.len();
+		let mut successors = Vec::with_capacity(len);
+		let mut reads = Vec::with_capacity(len);
+		let mut kills = Vec::with_capacity(len);
+		for (pc, ins) in self.decoded_instructions() {
+			let mut next = Vec::new();
+			let ins = match ins {
+				Some(ins) => ins,
+				None => {
+					if pc + 1 < len {
+						next.push(pc + 1);
+					}
+					successors.push(next);
+					reads.push(Vec::new());
+					kills.push(Vec::new());
+					continue;
+				}
+			};
+			if falls_through(&ins) && pc + 1 < len {
+				next.push(pc + 1);
+			}
+			if let Some(to) = branch_target(pc, &ins) {
+				if to < len && !next.contains(&to) {
+					next.push(to);
+				}
+			}
+			let mut effects = ins.reg_effects();
+			if let DecodedInstruction::ABx(Opcode::Closure, _, bx) = ins {
+				if let Some(proto) = self.protos.get(bx as usize) {
+					for upvalue in &proto.upvalues {
+						if let Upvalue::Stack(reg) = *upvalue {
+							effects.reads.push(reg as u32);
+						}
+					}
+				}
+			}
+			let kill = match ins.opcode() {
+				Opcode::TestSet | Opcode::TForLoop => Vec::new(),
+				Opcode::ForLoop => effects.writes[..1].to_vec(),
+				_ => effects.writes,
+			};
+			successors.push(next);
+			reads.push(effects.reads);
+			kills.push(kill);
+		}
+
+		let mut live_in = vec![BTreeSet::new(); len];
+		let mut live_out = vec![BTreeSet::new(); len];
+		let mut changed = true;
+		while changed {
+			changed = false;
+			for pc in (0..len).rev() {
+				let out: BTreeSet<u32> = successors[pc].iter()
+					.flat_map(|&next| live_in[next].iter().cloned())
+					.collect();
+				let mut live = out.clone();
+				for reg in &kills[pc] {
+					live.remove(reg);
+				}
+				live.extend(reads[pc].iter().cloned());
+				if live != live_in[pc] {
+					live_in[pc] = live;
+					changed = true;
+				}
+				live_out[pc] = out;
+			}
+		}
+		let to_vecs = |sets: Vec<BTreeSet<u32>>| {
+			sets.into_iter().map(|set| set.into_iter().collect()).collect()
+		};
+		Liveness {
+			live_in: to_vecs(live_in),
+			live_out: to_vecs(live_out),
+		}
+	}
+
+	/// Set `max_stack_size` from the registers the function's code uses.
+	///
+	/// The result covers the parameters and every register an instruction
+	/// refers to, and is never less than 2, the minimum Lua's compiler
+	/// reserves. Registers beyond 254 are clamped to the format's limit.
+	pub fn recompute_max_stack(&mut self) {
+		let mut size = cmp::max(self.num_params as u32, 2);
+		for (_, ins) in self.decoded_instructions() {
+			if let Some(reg) = ins.as_ref().and_then(max_register) {
+				size = cmp::max(size, reg + 1);
+			}
+		}
+		self.max_stack_size = cmp::min(size, 255) as u8;
+	}
+}
@@ -0,0 +1,364 @@
+//! Assembly of functions from the text format produced by `disassemble`.
+
+use std::{error, fmt};
+use std::str::FromStr;
+
+use bytecode::{self, ArgMode, OpMode, Opcode, RK, BITRK, MAXARG_A, MAXARG_AX, MAXARG_B, MAXARG_BX, MAXINDEXRK, MIN_SBX, MAX_SBX};
+use super::{Constant, Function, LuaString, Upvalue};
+
+/// An error encountered while assembling text into a function.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AssembleError {
+	/// The 1-based line number the error occurred on.
+	pub line: usize,
+	/// A description of the error.
+	pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "line {}: {}", self.line, self.message)
+	}
+}
+
+impl error::Error for AssembleError {
+	fn description(&self) -> &str {
+		&self.message
+	}
+}
+
+/// Assemble a function from the text format produced by
+/// `Function::disassemble`.
+///
+/// The result has no debug information. Everything after a `;` outside of
+/// a string is a comment, so the annotations `disassemble` writes after
+/// instructions are ignored.
+pub fn assemble(text: &str) -> Result<Function, AssembleError> {
+	let mut stack: Vec<Function> = Vec::new();
+	let mut result = None;
+	for (i, line) in text.lines().enumerate() {
+		let error = |message: String| AssembleError { line: i + 1, message };
+		let tokens = try!(tokenize(line).map_err(&error));
+		if tokens.is_empty() {
+			continue;
+		}
+		if result.is_some() {
+			return Err(error("unexpected text after the outermost function".to_owned()));
+		}
+		match tokens[0] {
+			Token::Word(ref word) if word == "function" => {
+				try!(expect_len(&tokens, 1).map_err(&error));
+				stack.push(Function::default());
+			}
+			Token::Word(ref word) if word == "end" => {
+				try!(expect_len(&tokens, 1).map_err(&error));
+				let function = try!(stack.pop().ok_or_else(|| error("unmatched end".to_owned())));
+				match stack.last_mut() {
+					Some(parent) => parent.protos.push(function),
+					None => result = Some(function),
+				}
+			}
+			_ => match stack.last_mut() {
+				Some(function) => try!(directive(function, &tokens).map_err(&error)),
+				None => return Err(error("expected function".to_owned())),
+			},
+		}
+	}
+	result.ok_or_else(|| AssembleError {
+		line: text.lines().count(),
+		message: "unexpected end of text".to_owned(),
+	})
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+	Word(String),
+	Str(Vec<u8>),
+}
+
+/// Split a line into words and quoted strings, stopping at a comment.
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+	let bytes = line.as_bytes();
+	let mut tokens = Vec::new();
+	let mut i = 0;
+	while i < bytes.len() {
+		match bytes[i] {
+			b' ' | b'\t' | b'\r' => i += 1,
+			b';' => break,
+			b'"' => {
+				let mut string = Vec::new();
+				i += 1;
+				loop {
+					match bytes.get(i).cloned() {
+						None => return Err("unterminated string".to_owned()),
+						Some(b'"') => break,
+						Some(b'\\') => {
+							let (byte, len) = try!(unescape(&bytes[i + 1..]));
+							string.push(byte);
+							i += 1 + len;
+						}
+						Some(b) => {
+							string.push(b);
+							i += 1;
+						}
+					}
+				}
+				tokens.push(Token::Str(string));
+				i += 1;
+			}
+			_ => {
+				let start = i;
+				while i < bytes.len() && !b" \t\r;\"".contains(&bytes[i]) {
+					i += 1;
+				}
+				tokens.push(Token::Word(line[start..i].to_owned()));
+			}
+		}
+	}
+	Ok(tokens)
+}
+
+/// Decode the escape sequence at the start of `rest`, which follows a
+/// backslash, returning the byte and the length of the sequence.
+fn unescape(rest: &[u8]) -> Result<(u8, usize), String> {
+	let byte = match rest.first().cloned() {
+		Some(b'a') => 0x07,
+		Some(b'b') => 0x08,
+		Some(b'f') => 0x0c,
+		Some(b'n') => b'\n',
+		Some(b'r') => b'\r',
+		Some(b't') => b'\t',
+		Some(b'v') => 0x0b,
+		Some(b'\\') => b'\\',
+		Some(b'"') => b'"',
+		Some(b) if b.is_ascii_digit() => {
+			let len = rest.iter().take(3).take_while(|b| b.is_ascii_digit()).count();
+			let value = rest[..len].iter().fold(0u32, |n, &b| n * 10 + (b - b'0') as u32);
+			if value > 0xff {
+				return Err(format!("escape \\{} out of range", value));
+			}
+			return Ok((value as u8, len));
+		}
+		_ => return Err("invalid escape sequence".to_owned()),
+	};
+	Ok((byte, 1))
+}
+
+fn expect_len(tokens: &[Token], len: usize) -> Result<(), String> {
+	if tokens.len() != len {
+		return Err(format!("expected {} fields but found {}", len, tokens.len()));
+	}
+	Ok(())
+}
+
+fn word(token: &Token) -> Result<&str, String> {
+	match *token {
+		Token::Word(ref word) => Ok(word),
+		Token::Str(_) => Err("expected a word but found a string".to_owned()),
+	}
+}
+
+fn string(token: &Token) -> Result<LuaString, String> {
+	match *token {
+		Token::Str(ref bytes) => Ok(LuaString::new(&bytes[..])),
+		Token::Word(ref word) => Err(format!("expected a string but found {}", word)),
+	}
+}
+
+fn number<T: FromStr>(token: &Token) -> Result<T, String> {
+	let word = try!(word(token));
+	word.parse().map_err(|_| format!("invalid number {}", word))
+}
+
+fn hex_u64(token: &Token) -> Result<u64, String> {
+	let word = try!(word(token));
+	let hex = match word.strip_prefix("0x") {
+		Some(hex) => hex,
+		None => return Err(format!("expected a hexadecimal number but found {}", word)),
+	};
+	u64::from_str_radix(hex, 16).map_err(|_| format!("invalid hexadecimal number {}", word))
+}
+
+fn boolean(token: &Token) -> Result<bool, String> {
+	match try!(word(token)) {
+		"true" => Ok(true),
+		"false" => Ok(false),
+		other => Err(format!("expected true or false but found {}", other)),
+	}
+}
+
+/// Apply a line other than `function` or `end` to the function being
+/// assembled.
+fn directive(function: &mut Function, tokens: &[Token]) -> Result<(), String> {
+	match try!(word(&tokens[0])) {
+		"source" => {
+			try!(expect_len(tokens, 2));
+			function.source = Some(try!(string(&tokens[1])));
+		}
+		"lines" => {
+			try!(expect_len(tokens, 3));
+			function.line_start = try!(number(&tokens[1]));
+			function.line_end = try!(number(&tokens[2]));
+		}
+		"params" => {
+			try!(expect_len(tokens, 2));
+			function.num_params = try!(number(&tokens[1]));
+		}
+		"vararg" => {
+			try!(expect_len(tokens, 2));
+			function.is_vararg = try!(number(&tokens[1]));
+		}
+		"maxstack" => {
+			try!(expect_len(tokens, 2));
+			function.max_stack_size = try!(number(&tokens[1]));
+		}
+		"upvalue" => {
+			try!(expect_len(tokens, 3));
+			let index = try!(number(&tokens[2]));
+			function.upvalues.push(match try!(word(&tokens[1])) {
+				"stack" => Upvalue::Stack(index),
+				"outer" => Upvalue::Outer(index),
+				other => return Err(format!("unknown upvalue kind {}", other)),
+			});
+		}
+		"constant" => {
+			let constant = try!(constant(tokens));
+			function.constants.push(constant);
+		}
+		_ => {
+			let ins = try!(instruction(tokens));
+			function.code.push(ins);
+		}
+	}
+	Ok(())
+}
+
+fn constant(tokens: &[Token]) -> Result<Constant, String> {
+	let kind = try!(tokens.get(1).ok_or_else(|| "expected a constant kind".to_owned()));
+	Ok(match try!(word(kind)) {
+		"nil" => {
+			try!(expect_len(tokens, 2));
+			Constant::Nil
+		}
+		"true" | "false" => {
+			try!(expect_len(tokens, 2));
+			Constant::Boolean(try!(boolean(kind)))
+		}
+		"int" => {
+			try!(expect_len(tokens, 3));
+			Constant::Int(try!(number(&tokens[2])))
+		}
+		"float" => {
+			try!(expect_len(tokens, 3));
+			Constant::Float(try!(number(&tokens[2])))
+		}
+		"floatbits" => {
+			try!(expect_len(tokens, 3));
+			Constant::Float(f64::from_bits(try!(hex_u64(&tokens[2]))))
+		}
+		"rawfloat" => {
+			try!(expect_len(tokens, 3));
+			let hex = try!(word(&tokens[2]));
+			if hex.len() % 2 != 0 {
+				return Err(format!("odd number of digits in {}", hex));
+			}
+			let mut bytes = Vec::with_capacity(hex.len() / 2);
+			for i in 0..hex.len() / 2 {
+				bytes.push(try!(u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+					.map_err(|_| format!("invalid hexadecimal bytes {}", hex))));
+			}
+			Constant::RawFloat(bytes)
+		}
+		"string" => {
+			try!(expect_len(tokens, 3));
+			Constant::ShortString(try!(string(&tokens[2])))
+		}
+		"longstring" => {
+			try!(expect_len(tokens, 3));
+			Constant::LongString(try!(string(&tokens[2])))
+		}
+		other => return Err(format!("unknown constant kind {}", other)),
+	})
+}
+
+/// Parse a line holding a single instruction.
+pub fn parse_instruction(line: &str) -> Result<u32, String> {
+	let tokens = try!(tokenize(line));
+	if tokens.is_empty() {
+		return Err("expected an instruction".to_owned());
+	}
+	instruction(&tokens)
+}
+
+/// Parse an instruction in the form `Function::annotate_instruction`
+/// writes, or a raw `.word`.
+fn instruction(tokens: &[Token]) -> Result<u32, String> {
+	let name = try!(word(&tokens[0]));
+	if name == ".word" {
+		try!(expect_len(tokens, 2));
+		let value = try!(hex_u64(&tokens[1]));
+		return in_range(value as i64, 0, u32::MAX as i64).map(|v| v as u32);
+	}
+	let op = try!(Opcode::from_name(name).ok_or_else(|| format!("unknown opcode {}", name)));
+	let (b_mode, c_mode) = op.arg_modes();
+	let operands: Vec<i64> = try!(tokens[1..].iter().map(number).collect());
+	let expected = match op.mode() {
+		OpMode::ABC => 1 + (b_mode != ArgMode::Unused) as usize + (c_mode != ArgMode::Unused) as usize,
+		OpMode::ABx => 1 + (b_mode != ArgMode::Unused) as usize,
+		OpMode::AsBx => 2,
+		OpMode::Ax => 1,
+	};
+	if operands.len() != expected {
+		return Err(format!("expected {} operands for {} but found {}", expected, name, operands.len()));
+	}
+
+	let a = match op.mode() {
+		OpMode::Ax => 0,
+		_ => try!(in_range(operands[0], 0, MAXARG_A as i64)) as u8,
+	};
+	Ok(match op.mode() {
+		OpMode::ABC => {
+			let mut rest = operands[1..].iter();
+			let b = if b_mode == ArgMode::Unused { 0 } else { try!(operand(b_mode, *rest.next().unwrap())) };
+			let c = if c_mode == ArgMode::Unused { 0 } else { try!(operand(c_mode, *rest.next().unwrap())) };
+			bytecode::encode(op, a, b, c)
+		}
+		OpMode::ABx => {
+			let bx = match b_mode {
+				ArgMode::Unused => 0,
+				ArgMode::Constant => try!(in_range(-1 - operands[1], 0, MAXARG_BX as i64)),
+				_ => try!(in_range(operands[1], 0, MAXARG_BX as i64)),
+			};
+			bytecode::encode_bx(op, a, bx as u32)
+		}
+		OpMode::AsBx => {
+			let sbx = try!(in_range(operands[1], MIN_SBX as i64, MAX_SBX as i64));
+			bytecode::encode_sbx(op, a, sbx as i32)
+		}
+		OpMode::Ax => {
+			let ax = try!(in_range(-1 - operands[0], 0, MAXARG_AX as i64));
+			bytecode::encode_ax(op, ax as u32)
+		}
+	})
+}
+
+fn in_range(value: i64, min: i64, max: i64) -> Result<i64, String> {
+	if value < min || value > max {
+		return Err(format!("operand {} out of range", value));
+	}
+	Ok(value)
+}
+
+/// Decode a `B` or `C` operand, where constants are shown as `-1 - k`.
+fn operand(mode: ArgMode, value: i64) -> Result<u32, String> {
+	if mode == ArgMode::Constant {
+		let rk = if value < 0 {
+			RK::K(try!(in_range(-1 - value, 0, MAXINDEXRK as i64)) as u8)
+		} else {
+			RK::R(try!(in_range(value, 0, BITRK as i64 - 1)) as u8)
+		};
+		Ok(rk.encode())
+	} else {
+		in_range(value, 0, MAXARG_B as i64).map(|v| v as u32)
+	}
+}
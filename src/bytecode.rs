@@ -1,6 +1,54 @@
 //! Tools for bytecode generation.
 
-const BITRK: u32 = 1 << 8;
+use std::{error, fmt};
+
+use super::Version;
+
+/// The width of the opcode field, in bits.
+pub const SIZE_OP: u32 = 6;
+/// The width of the `A` parameter, in bits.
+pub const SIZE_A: u32 = 8;
+/// The width of the `B` parameter, in bits.
+pub const SIZE_B: u32 = 9;
+/// The width of the `C` parameter, in bits.
+pub const SIZE_C: u32 = 9;
+/// The width of the `Bx` parameter, in bits.
+pub const SIZE_BX: u32 = SIZE_B + SIZE_C;
+/// The width of the `Ax` parameter, in bits.
+pub const SIZE_AX: u32 = SIZE_A + SIZE_BX;
+
+const POS_A: u32 = SIZE_OP;
+const POS_C: u32 = POS_A + SIZE_A;
+const POS_B: u32 = POS_C + SIZE_C;
+const POS_BX: u32 = POS_C;
+const POS_AX: u32 = POS_A;
+
+/// The bit of an `RK` parameter which marks it as a constant index.
+///
+/// Custom builds of Lua with wider `B` and `C` parameters move it, so it
+/// is derived from their width rather than fixed at 256.
+pub const BITRK: u32 = 1 << (SIZE_B - 1);
+
+/// The largest value of an `A` parameter.
+pub const MAXARG_A: u32 = (1 << SIZE_A) - 1;
+/// The largest value of a `B` parameter.
+pub const MAXARG_B: u32 = (1 << SIZE_B) - 1;
+/// The largest value of a `C` parameter.
+pub const MAXARG_C: u32 = (1 << SIZE_C) - 1;
+/// The largest value of a `Bx` parameter.
+pub const MAXARG_BX: u32 = (1 << SIZE_BX) - 1;
+/// The bias of an `sBx` parameter, which is also the magnitude of the most
+/// negative value it can hold.
+pub const MAXARG_SBX: i32 = (MAXARG_BX >> 1) as i32;
+/// The smallest value of an `sBx` parameter.
+pub const MIN_SBX: i32 = -MAXARG_SBX;
+/// The largest value of an `sBx` parameter. The bias rounds down, so this
+/// is one more than `MAXARG_SBX`.
+pub const MAX_SBX: i32 = MAXARG_BX as i32 - MAXARG_SBX;
+/// The largest value of an `Ax` parameter.
+pub const MAXARG_AX: u32 = (1 << SIZE_AX) - 1;
+/// The largest constant index which can be used as an `RK` parameter.
+pub const MAXINDEXRK: u32 = BITRK - 1;
 
 /// A slot which is either a register (`R`) or constant (`K`).
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
@@ -31,22 +79,151 @@ impl RK {
 
 /// Encode an instruction with `A`, `B`, and `C` parameters.
 pub fn encode(op: Opcode, a: u8, b: u32, c: u32) -> u32 {
-	(op as u32) | ((a as u32) << 6) | ((c & 0x1ff) << 14) | ((b & 0x1ff) << 23)
+	(op as u32) | ((a as u32) << POS_A) | ((c & MAXARG_C) << POS_C) | ((b & MAXARG_B) << POS_B)
 }
 
 /// Encode an instruction with `A` and `Bx` parameters.
 pub fn encode_bx(op: Opcode, a: u8, bx: u32) -> u32 {
-	(op as u32) | ((a as u32) << 6) | ((bx & 0x3ffff) << 14)
+	(op as u32) | ((a as u32) << POS_A) | ((bx & MAXARG_BX) << POS_BX)
 }
 
 /// Encode an instruction with `A` and `sBx` parameters.
+///
+/// `sbx` must be between `MIN_SBX` and `MAX_SBX`; use `try_encode_sbx` when
+/// it might not be.
 pub fn encode_sbx(op: Opcode, a: u8, sbx: i32) -> u32 {
-	(op as u32) | ((a as u32) << 6) | ((((sbx + 0x20000) as u32) & 0x3ffff) << 14)
+	debug_assert!((MIN_SBX..=MAX_SBX).contains(&sbx), "sBx {} out of range", sbx);
+	encode_bx(op, a, (sbx + MAXARG_SBX) as u32)
+}
+
+/// Encode an instruction with `A` and `sBx` parameters, failing if `sbx`
+/// doesn't fit rather than wrapping.
+pub fn try_encode_sbx(op: Opcode, a: u8, sbx: i32) -> Result<u32, SbxOutOfRange> {
+	if !(MIN_SBX..=MAX_SBX).contains(&sbx) {
+		return Err(SbxOutOfRange(sbx));
+	}
+	Ok(encode_sbx(op, a, sbx))
+}
+
+/// An `sBx` parameter outside of `MIN_SBX` to `MAX_SBX`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SbxOutOfRange(pub i32);
+
+impl fmt::Display for SbxOutOfRange {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "sBx {} is not between {} and {}", self.0, MIN_SBX, MAX_SBX)
+	}
+}
+
+impl error::Error for SbxOutOfRange {
+	fn description(&self) -> &str {
+		"sBx out of range"
+	}
 }
 
 /// Encode an instruction with an `Ax` parameter.
 pub fn encode_ax(op: Opcode, ax: u32) -> u32 {
-	(op as u32) | ((ax & 0x3ffffff) << 6)
+	(op as u32) | ((ax & MAXARG_AX) << POS_AX)
+}
+
+/// An instruction split into its opcode and parameters.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DecodedInstruction {
+	/// An instruction with `A`, `B`, and `C` parameters.
+	ABC(Opcode, u8, u32, u32),
+	/// An instruction with `A` and `Bx` parameters.
+	ABx(Opcode, u8, u32),
+	/// An instruction with `A` and `sBx` parameters.
+	AsBx(Opcode, u8, i32),
+	/// An instruction with an `Ax` parameter.
+	Ax(Opcode, u32),
+}
+
+impl DecodedInstruction {
+	/// The instruction's opcode.
+	pub fn opcode(&self) -> Opcode {
+		match *self {
+			DecodedInstruction::ABC(op, ..) => op,
+			DecodedInstruction::ABx(op, ..) => op,
+			DecodedInstruction::AsBx(op, ..) => op,
+			DecodedInstruction::Ax(op, ..) => op,
+		}
+	}
+	/// Convert this instruction back to a number.
+	pub fn encode(&self) -> u32 {
+		match *self {
+			DecodedInstruction::ABC(op, a, b, c) => encode(op, a, b, c),
+			DecodedInstruction::ABx(op, a, bx) => encode_bx(op, a, bx),
+			DecodedInstruction::AsBx(op, a, sbx) => encode_sbx(op, a, sbx),
+			DecodedInstruction::Ax(op, ax) => encode_ax(op, ax),
+		}
+	}
+}
+
+/// Decode an instruction according to the format of its opcode.
+///
+/// Returns `None` if the opcode is not a valid Lua opcode.
+pub fn decode(ins: u32) -> Option<DecodedInstruction> {
+	let op = Opcode::from_u8((ins & ((1 << SIZE_OP) - 1)) as u8)?;
+	let a = ((ins >> POS_A) & MAXARG_A) as u8;
+	let bx = (ins >> POS_BX) & MAXARG_BX;
+	Some(match op.mode() {
+		OpMode::ABC => DecodedInstruction::ABC(op, a, (ins >> POS_B) & MAXARG_B, (ins >> POS_C) & MAXARG_C),
+		OpMode::ABx => DecodedInstruction::ABx(op, a, bx),
+		OpMode::AsBx => DecodedInstruction::AsBx(op, a, bx as i32 - MAXARG_SBX),
+		OpMode::Ax => DecodedInstruction::Ax(op, (ins >> POS_AX) & MAXARG_AX),
+	})
+}
+
+/// An iterator over the decoded instructions of a function, paired with
+/// their program counters.
+pub struct DecodedInstructions<'a> {
+	iter: ::std::iter::Enumerate<::std::slice::Iter<'a, u32>>,
+}
+
+impl<'a> DecodedInstructions<'a> {
+	/// Decode the instructions of the given code.
+	pub fn new(code: &'a [u32]) -> DecodedInstructions<'a> {
+		DecodedInstructions { iter: code.iter().enumerate() }
+	}
+}
+
+impl<'a> Iterator for DecodedInstructions<'a> {
+	type Item = (usize, Option<DecodedInstruction>);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.iter.next().map(|(pc, &ins)| (pc, decode(ins)))
+	}
+
+	fn size_hint(&self) -> (usize, Option<usize>) {
+		self.iter.size_hint()
+	}
+}
+
+/// The parameter format of an opcode.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OpMode {
+	/// `A`, `B`, and `C` parameters.
+	ABC,
+	/// `A` and `Bx` parameters.
+	ABx,
+	/// `A` and `sBx` parameters.
+	AsBx,
+	/// An `Ax` parameter.
+	Ax,
+}
+
+/// How an instruction uses its `B` or `C` parameter.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArgMode {
+	/// The parameter is not used.
+	Unused,
+	/// The parameter is used, but not as a register or constant.
+	Used,
+	/// The parameter is a register index or jump offset.
+	Register,
+	/// The parameter is a constant index or `RK` slot.
+	Constant,
 }
 
 // LSB 6      8         9         9  MSB
@@ -58,7 +235,7 @@ pub fn encode_ax(op: Opcode, ax: u32) -> u32 {
 
 /// A Lua opcode.
 #[repr(u8)]
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum Opcode { // Args   Action
 	Move,     // A B    R(A) := R(B)
 	LoadK,    // A Bx   R(A) := Kst(Bx)
@@ -127,3 +304,124 @@ pub enum Opcode { // Args   Action
     //    set top (like in OP_CALL with C == 0).
 	ExtraArg, // Ax     extra (larger) argument for previous opcode
 }
+
+const OPCODES: [Opcode; 47] = [
+	Opcode::Move, Opcode::LoadK, Opcode::LoadKX, Opcode::LoadBool, Opcode::LoadNil,
+	Opcode::GetUpval, Opcode::GetTabUp, Opcode::GetTable,
+	Opcode::SetTabUp, Opcode::SetUpval, Opcode::SetTable,
+	Opcode::NewTable, Opcode::Self_,
+	Opcode::Add, Opcode::Sub, Opcode::Mul, Opcode::Mod, Opcode::Pow, Opcode::Div,
+	Opcode::IntDiv, Opcode::BinAnd, Opcode::BinOr, Opcode::BinXor, Opcode::ShLeft,
+	Opcode::ShRight, Opcode::UnMinus, Opcode::BinNot, Opcode::Not, Opcode::Len,
+	Opcode::Concat,
+	Opcode::Jump, Opcode::Eq, Opcode::Less, Opcode::LessEq, Opcode::Test, Opcode::TestSet,
+	Opcode::Call, Opcode::TailCall, Opcode::Return,
+	Opcode::ForLoop, Opcode::ForPrep, Opcode::TForCall, Opcode::TForLoop, Opcode::SetList,
+	Opcode::Closure, Opcode::VarArg, Opcode::ExtraArg,
+];
+
+const NAMES: [&'static str; 47] = [
+	"MOVE", "LOADK", "LOADKX", "LOADBOOL", "LOADNIL", "GETUPVAL", "GETTABUP",
+	"GETTABLE", "SETTABUP", "SETUPVAL", "SETTABLE", "NEWTABLE", "SELF",
+	"ADD", "SUB", "MUL", "MOD", "POW", "DIV", "IDIV", "BAND", "BOR",
+	"BXOR", "SHL", "SHR", "UNM", "BNOT", "NOT", "LEN", "CONCAT", "JMP",
+	"EQ", "LT", "LE", "TEST", "TESTSET", "CALL", "TAILCALL", "RETURN",
+	"FORLOOP", "FORPREP", "TFORCALL", "TFORLOOP", "SETLIST", "CLOSURE",
+	"VARARG", "EXTRAARG",
+];
+
+impl Opcode {
+	/// The name of this opcode as used by `luac -l`.
+	pub fn name(&self) -> &'static str {
+		NAMES[*self as usize]
+	}
+
+	/// Whether the given version of Lua has an opcode with the same meaning.
+	///
+	/// Opcodes are numbered differently in each version, so this concerns
+	/// only whether an equivalent exists, not whether it is encoded the
+	/// same way.
+	pub fn exists_in(&self, version: Version) -> bool {
+		match *self {
+			Opcode::IntDiv | Opcode::BinAnd | Opcode::BinOr | Opcode::BinXor |
+			Opcode::ShLeft | Opcode::ShRight | Opcode::BinNot => version >= Version::Lua53,
+			// 5.1 accesses globals directly rather than through `_ENV`, and
+			// its TFORLOOP performs the call itself.
+			Opcode::LoadKX | Opcode::GetTabUp | Opcode::SetTabUp |
+			Opcode::TForCall | Opcode::ExtraArg => version >= Version::Lua52,
+			_ => true,
+		}
+	}
+
+	/// Look up an opcode by the name `luac -l` uses for it.
+	pub fn from_name(name: &str) -> Option<Opcode> {
+		NAMES.iter().position(|&n| n == name).map(|i| OPCODES[i])
+	}
+
+	/// Convert a number to an `Opcode`, if it is in range.
+	pub fn from_u8(value: u8) -> Option<Opcode> {
+		OPCODES.get(value as usize).cloned()
+	}
+
+	/// The parameter format of this opcode.
+	pub fn mode(&self) -> OpMode {
+		match *self {
+			Opcode::LoadK | Opcode::LoadKX | Opcode::Closure => OpMode::ABx,
+			Opcode::Jump | Opcode::ForLoop | Opcode::ForPrep | Opcode::TForLoop => OpMode::AsBx,
+			Opcode::ExtraArg => OpMode::Ax,
+			_ => OpMode::ABC,
+		}
+	}
+
+	/// How this opcode uses its `B` and `C` parameters.
+	pub fn arg_modes(&self) -> (ArgMode, ArgMode) {
+		use self::ArgMode::*;
+		match *self {
+			Opcode::Move => (Register, Unused),
+			Opcode::LoadK => (Constant, Unused),
+			Opcode::LoadKX => (Unused, Unused),
+			Opcode::LoadBool => (Used, Used),
+			Opcode::LoadNil => (Used, Unused),
+			Opcode::GetUpval => (Used, Unused),
+			Opcode::GetTabUp => (Used, Constant),
+			Opcode::GetTable => (Register, Constant),
+			Opcode::SetTabUp => (Constant, Constant),
+			Opcode::SetUpval => (Used, Unused),
+			Opcode::SetTable => (Constant, Constant),
+			Opcode::NewTable => (Used, Used),
+			Opcode::Self_ => (Register, Constant),
+			Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Mod | Opcode::Pow |
+			Opcode::Div | Opcode::IntDiv | Opcode::BinAnd | Opcode::BinOr |
+			Opcode::BinXor | Opcode::ShLeft | Opcode::ShRight => (Constant, Constant),
+			Opcode::UnMinus | Opcode::BinNot | Opcode::Not | Opcode::Len => (Register, Unused),
+			Opcode::Concat => (Register, Register),
+			Opcode::Jump => (Register, Unused),
+			Opcode::Eq | Opcode::Less | Opcode::LessEq => (Constant, Constant),
+			Opcode::Test => (Unused, Used),
+			Opcode::TestSet => (Register, Used),
+			Opcode::Call | Opcode::TailCall => (Used, Used),
+			Opcode::Return => (Used, Unused),
+			Opcode::ForLoop | Opcode::ForPrep => (Register, Unused),
+			Opcode::TForCall => (Unused, Used),
+			Opcode::TForLoop => (Register, Unused),
+			Opcode::SetList => (Used, Used),
+			Opcode::Closure => (Used, Unused),
+			Opcode::VarArg => (Used, Unused),
+			Opcode::ExtraArg => (Used, Used),
+		}
+	}
+
+	/// Whether this opcode assigns to register `A`.
+	pub fn sets_a(&self) -> bool {
+		!matches!(*self,
+			Opcode::SetTabUp | Opcode::SetUpval | Opcode::SetTable |
+			Opcode::Jump | Opcode::Eq | Opcode::Less | Opcode::LessEq | Opcode::Test |
+			Opcode::Return | Opcode::TForCall | Opcode::SetList | Opcode::ExtraArg)
+	}
+
+	/// Whether this opcode is a test, which conditionally skips the next
+	/// instruction.
+	pub fn is_test(&self) -> bool {
+		matches!(*self, Opcode::Eq | Opcode::Less | Opcode::LessEq | Opcode::Test | Opcode::TestSet)
+	}
+}
@@ -0,0 +1,198 @@
+//! Structural comparison of chunks.
+
+use std::fmt;
+use std::io::Write;
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use bytecode::{self, SIZE_OP};
+use edit::ConstantKey;
+use write::Fnv1a;
+use super::{Chunk, Constant, Function, Header, Instruction, ProtoPath};
+
+/// The differences between two chunks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkDiff {
+	/// The old and new headers, if they differ.
+	pub header: Option<(Header, Header)>,
+	/// The changes to functions present in both chunks.
+	pub functions: Vec<FunctionDiff>,
+	/// Functions present only in the new chunk.
+	pub added: Vec<ProtoPath>,
+	/// Functions present only in the old chunk.
+	pub removed: Vec<ProtoPath>,
+}
+
+/// The differences between two versions of a function.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionDiff {
+	/// The location of the function in both chunks.
+	pub path: ProtoPath,
+	/// The instructions which differ, as `(pc, old, new)`. An instruction
+	/// is `None` if the code on that side is too short to contain it.
+	pub instructions: Vec<(usize, Option<Instruction>, Option<Instruction>)>,
+	/// Constants present only in the new function.
+	pub constants_added: Vec<Constant>,
+	/// Constants present only in the old function.
+	pub constants_removed: Vec<Constant>,
+}
+
+impl ChunkDiff {
+	/// Whether the chunks had no differences.
+	pub fn is_empty(&self) -> bool {
+		self.header.is_none() && self.functions.is_empty() &&
+			self.added.is_empty() && self.removed.is_empty()
+	}
+}
+
+impl Chunk {
+	/// Compare this chunk to `other`, treating `other` as the new version.
+	///
+	/// Functions are matched by their path, and their instructions by
+	/// program counter. Debug information is not compared.
+	pub fn diff(&self, other: &Chunk) -> ChunkDiff {
+		let mut diff = ChunkDiff {
+			header: if self.header != other.header { Some((self.header, other.header)) } else { None },
+			functions: Vec::new(),
+			added: Vec::new(),
+			removed: Vec::new(),
+		};
+		diff_function(&self.main, &other.main, &mut Vec::new(), &mut diff);
+		diff
+	}
+}
+
+impl Function {
+	/// Compute a hash of the function's structure which is unchanged by
+	/// recompiling it.
+	///
+	/// The hash covers the parameter count, vararg flag, the sequence of
+	/// opcodes without their operands, and the constants as a multiset, so
+	/// reordering the constant table or changing debug information doesn't
+	/// affect it. Constants are compared as by `Constant::dedup_key`.
+	/// Nested functions are not included. Like `content_hash`, the hash is
+	/// 64-bit FNV-1a and stable across runs.
+	pub fn structural_signature(&self) -> u64 {
+		let mut hasher = Fnv1a::new();
+		let mut out = Vec::new();
+		out.push(self.num_params);
+		out.push(self.is_vararg);
+		out.extend(self.code.iter().map(|&ins| (ins & ((1 << SIZE_OP) - 1)) as u8));
+		hasher.write_all(&out).unwrap();
+
+		let mut constants: Vec<Vec<u8>> = self.constants.iter().map(|c| key_bytes(c.dedup_key())).collect();
+		constants.sort();
+		for constant in &constants {
+			hasher.write_all(constant).unwrap();
+		}
+		hasher.0
+	}
+}
+
+/// Encode a constant key unambiguously as bytes.
+fn key_bytes(key: ConstantKey) -> Vec<u8> {
+	let mut out = Vec::new();
+	match key {
+		ConstantKey::Nil => out.push(0),
+		ConstantKey::Boolean(b) => out.extend(&[1, b as u8]),
+		ConstantKey::Float(bits) => {
+			out.push(2);
+			out.write_u64::<LittleEndian>(bits).unwrap();
+		}
+		ConstantKey::Int(n) => {
+			out.push(3);
+			out.write_i64::<LittleEndian>(n).unwrap();
+		}
+		ConstantKey::RawFloat(bytes) => {
+			out.push(4);
+			out.write_u64::<LittleEndian>(bytes.len() as u64).unwrap();
+			out.extend(bytes);
+		}
+		ConstantKey::String(s) => {
+			out.push(5);
+			out.write_u64::<LittleEndian>(s.len() as u64).unwrap();
+			out.extend(s);
+		}
+	}
+	out
+}
+
+fn diff_function(old: &Function, new: &Function, path: &mut ProtoPath, diff: &mut ChunkDiff) {
+	let len = ::std::cmp::max(old.code.len(), new.code.len());
+	let instructions: Vec<_> = (0..len).filter_map(|pc| {
+		let (a, b) = (old.code.get(pc).cloned(), new.code.get(pc).cloned());
+		if a != b { Some((pc, a, b)) } else { None }
+	}).collect();
+	let constants_added = difference(&new.constants, &old.constants);
+	let constants_removed = difference(&old.constants, &new.constants);
+	if !instructions.is_empty() || !constants_added.is_empty() || !constants_removed.is_empty() {
+		diff.functions.push(FunctionDiff {
+			path: path.clone(),
+			instructions,
+			constants_added,
+			constants_removed,
+		});
+	}
+
+	for i in 0..::std::cmp::max(old.protos.len(), new.protos.len()) {
+		path.push(i);
+		match (old.protos.get(i), new.protos.get(i)) {
+			(Some(a), Some(b)) => diff_function(a, b, path, diff),
+			(Some(_), None) => diff.removed.push(path.clone()),
+			(None, Some(_)) => diff.added.push(path.clone()),
+			(None, None) => unreachable!(),
+		}
+		path.pop();
+	}
+}
+
+/// The constants in `a` which are not matched by one in `b`.
+fn difference(a: &[Constant], b: &[Constant]) -> Vec<Constant> {
+	let mut unmatched: Vec<&Constant> = b.iter().collect();
+	a.iter().filter(|c| {
+		match unmatched.iter().position(|u| u == c) {
+			Some(i) => { unmatched.remove(i); false }
+			None => true,
+		}
+	}).cloned().collect()
+}
+
+struct Ins(Option<Instruction>);
+
+impl fmt::Display for Ins {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.0 {
+			None => write!(f, "(none)"),
+			Some(ins) => match bytecode::decode(ins) {
+				Some(decoded) => write!(f, "{:?}", decoded),
+				None => write!(f, "{:#010x}", ins),
+			},
+		}
+	}
+}
+
+impl fmt::Display for ChunkDiff {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if let Some((ref old, ref new)) = self.header {
+			try!(writeln!(f, "header: {:?} -> {:?}", old, new));
+		}
+		for function in &self.functions {
+			try!(writeln!(f, "function {:?}:", function.path));
+			for &(pc, old, new) in &function.instructions {
+				try!(writeln!(f, "  [{}] {} -> {}", pc, Ins(old), Ins(new)));
+			}
+			for constant in &function.constants_removed {
+				try!(writeln!(f, "  - {:?}", constant));
+			}
+			for constant in &function.constants_added {
+				try!(writeln!(f, "  + {:?}", constant));
+			}
+		}
+		for path in &self.removed {
+			try!(writeln!(f, "function {:?}: removed", path));
+		}
+		for path in &self.added {
+			try!(writeln!(f, "function {:?}: added", path));
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,309 @@
+//! Human-readable listings of function code.
+
+use std::fmt::{self, Write};
+
+use bytecode::{self, ArgMode, DecodedInstruction, Opcode, RK};
+use asm;
+use super::{Constant, Function, Number, Upvalue};
+
+/// Format a number as `luac -l` does, with `%.14g` and a `.0` appended to
+/// values which would otherwise look like integers.
+pub fn format_number(n: Number) -> String {
+	if n.is_nan() {
+		return "nan".to_owned();
+	}
+	if n.is_infinite() {
+		return if n < 0.0 { "-inf" } else { "inf" }.to_owned();
+	}
+	let scientific = format!("{:.13e}", n);
+	let e = scientific.find('e').unwrap();
+	let exp: i32 = scientific[e + 1..].parse().unwrap();
+	let s = if !(-4..14).contains(&exp) {
+		format!("{}e{}{:02}", trim_zeros(scientific[..e].to_owned()), if exp < 0 { '-' } else { '+' }, exp.abs())
+	} else {
+		trim_zeros(format!("{:.*}", (13 - exp) as usize, n))
+	};
+	if s.bytes().all(|b| b == b'-' || b.is_ascii_digit()) {
+		s + ".0"
+	} else {
+		s
+	}
+}
+
+/// Remove trailing zeros after a decimal point, and the point if nothing
+/// is left after it.
+fn trim_zeros(mut s: String) -> String {
+	if s.contains('.') {
+		while s.ends_with('0') {
+			s.pop();
+		}
+		if s.ends_with('.') {
+			s.pop();
+		}
+	}
+	s
+}
+
+/// Write a string in double quotes, escaping it as `luac -l` does.
+pub fn write_quoted<W: Write>(out: &mut W, s: &[u8]) -> fmt::Result {
+	try!(out.write_char('"'));
+	for &b in s {
+		try!(match b {
+			b'"' => out.write_str("\\\""),
+			b'\\' => out.write_str("\\\\"),
+			0x07 => out.write_str("\\a"),
+			0x08 => out.write_str("\\b"),
+			0x0c => out.write_str("\\f"),
+			b'\n' => out.write_str("\\n"),
+			b'\r' => out.write_str("\\r"),
+			b'\t' => out.write_str("\\t"),
+			0x0b => out.write_str("\\v"),
+			_ if (0x20..0x7f).contains(&b) => out.write_char(b as char),
+			_ => write!(out, "\\{:03}", b),
+		});
+	}
+	out.write_char('"')
+}
+
+/// The operand `luac -l` shows for an `RK` parameter: the register, or
+/// `-1 - k` for constant `k`.
+fn rk_operand(value: u32) -> i64 {
+	match RK::decode(value) {
+		RK::R(r) => r as i64,
+		RK::K(k) => -1 - k as i64,
+	}
+}
+
+/// A function's code with jump targets named by labels.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Listing {
+	/// The program counter each label marks, in increasing order. The label
+	/// at index `i` is named `L{i + 1}`.
+	pub labels: Vec<usize>,
+	/// Each instruction as `Function::annotate_instruction` formats it,
+	/// except that jumps show their target label in place of `sBx`.
+	pub lines: Vec<String>,
+}
+
+impl Listing {
+	/// The name of the label at the given program counter, if any.
+	pub fn label_at(&self, pc: usize) -> Option<String> {
+		self.labels.binary_search(&pc).ok().map(|i| format!("L{}", i + 1))
+	}
+}
+
+/// Writes each label on its own line before the instruction it marks, and
+/// each instruction indented by a tab.
+impl fmt::Display for Listing {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for pc in 0..self.lines.len() + 1 {
+			if let Some(label) = self.label_at(pc) {
+				try!(writeln!(f, "{}:", label));
+			}
+			if let Some(line) = self.lines.get(pc) {
+				try!(writeln!(f, "\t{}", line));
+			}
+		}
+		Ok(())
+	}
+}
+
+impl Function {
+	/// Format the function's code as a listing, with a label at each target
+	/// of a jump or loop instruction.
+	///
+	/// A jump may target the end of the code, in which case its label
+	/// follows the last instruction. Jumps outside the code keep their raw
+	/// offset.
+	pub fn to_listing(&self) -> Listing {
+		let len = self.code.len();
+		let target = |pc: usize, sbx: i32| {
+			let to = pc as i64 + 1 + sbx as i64;
+			if to >= 0 && to as usize <= len { Some(to as usize) } else { None }
+		};
+		let mut labels: Vec<usize> = self.decoded_instructions().filter_map(|(pc, ins)| match ins {
+			Some(DecodedInstruction::AsBx(_, _, sbx)) => target(pc, sbx),
+			_ => None,
+		}).collect();
+		labels.sort();
+		labels.dedup();
+
+		let mut listing = Listing { labels, lines: Vec::with_capacity(len) };
+		for (pc, ins) in self.decoded_instructions() {
+			let line = match ins {
+				Some(DecodedInstruction::AsBx(op, a, sbx)) => match target(pc, sbx).and_then(|to| listing.label_at(to)) {
+					Some(label) => format!("{} {} {}", op.name(), a, label),
+					None => self.annotate_instruction(pc),
+				},
+				_ => self.annotate_instruction(pc),
+			};
+			listing.lines.push(line);
+		}
+		listing
+	}
+
+	/// Format the instruction at `pc` in the style of `luac -l`.
+	///
+	/// The line holds the opcode's name and its operands, with constant
+	/// operands shown as `-1 - k`. A comment after `;` resolves constants
+	/// from the constant table and upvalue names from the debug info, for
+	/// example `GETTABUP 0 0 -1 ; _ENV["print"]`. Unlike `luac -l`, jump
+	/// targets are 0-based program counters and closures name the index
+	/// into `protos`.
+	///
+	/// Panics if `pc` is out of range.
+	pub fn annotate_instruction(&self, pc: usize) -> String {
+		let raw = self.code[pc];
+		let ins = match bytecode::decode(raw) {
+			Some(ins) => ins,
+			None => return format!("{:#010x} ; invalid opcode", raw),
+		};
+		let op = ins.opcode();
+		let (b_mode, c_mode) = op.arg_modes();
+		let mut line = format!("{} ", op.name());
+		match ins {
+			DecodedInstruction::ABC(_, a, b, c) => {
+				write!(line, "{}", a).unwrap();
+				for &(mode, value) in &[(b_mode, b), (c_mode, c)] {
+					match mode {
+						ArgMode::Unused => {}
+						ArgMode::Constant => write!(line, " {}", rk_operand(value)).unwrap(),
+						_ => write!(line, " {}", value).unwrap(),
+					}
+				}
+			}
+			DecodedInstruction::ABx(_, a, bx) => {
+				write!(line, "{}", a).unwrap();
+				match b_mode {
+					ArgMode::Unused => {}
+					ArgMode::Constant => write!(line, " {}", -1 - bx as i64).unwrap(),
+					_ => write!(line, " {}", bx).unwrap(),
+				}
+			}
+			DecodedInstruction::AsBx(_, a, sbx) => write!(line, "{} {}", a, sbx).unwrap(),
+			DecodedInstruction::Ax(_, ax) => write!(line, "{}", -1 - ax as i64).unwrap(),
+		}
+
+		let comment = self.annotation(pc, &ins);
+		if !comment.is_empty() {
+			line.push_str(" ; ");
+			line.push_str(&comment);
+		}
+		line
+	}
+
+	/// Write the function and its nested functions as text which `assemble`
+	/// reads back into an equal function, apart from debug information.
+	///
+	/// Each function is a `function` ... `end` block holding its source,
+	/// line range, parameter count, vararg flag, stack size, upvalues,
+	/// constants, code, and then nested functions. Instructions are written
+	/// as by `annotate_instruction`, except that any which would not read
+	/// back exactly, such as those with invalid opcodes or nonzero unused
+	/// parameters, are written as a raw `.word`.
+	pub fn disassemble(&self) -> String {
+		let mut out = String::new();
+		disassemble(self, 0, &mut out).unwrap();
+		out
+	}
+
+	/// The comment `annotate_instruction` places after an instruction.
+	fn annotation(&self, pc: usize, ins: &DecodedInstruction) -> String {
+		let constant = |k: u32| self.constants.get(k as usize).map_or("?".to_owned(), |c| c.to_string());
+		let rk = |value: u32| match RK::decode(value) {
+			RK::K(k) => Some(constant(k as u32)),
+			RK::R(_) => None,
+		};
+		let upvalue = |i: u32| self.debug.upvalues.get(i as usize).map_or("-".to_owned(), |s| s.to_string());
+
+		match *ins {
+			DecodedInstruction::ABx(Opcode::LoadK, _, bx) => constant(bx),
+			DecodedInstruction::ABx(Opcode::Closure, _, bx) => format!("proto {}", bx),
+			DecodedInstruction::Ax(Opcode::ExtraArg, ax) => constant(ax),
+			DecodedInstruction::AsBx(_, _, sbx) => format!("to {}", pc as i64 + 1 + sbx as i64),
+			DecodedInstruction::ABC(op, a, b, c) => match op {
+				Opcode::GetUpval | Opcode::SetUpval => upvalue(b),
+				Opcode::GetTabUp => match rk(c) {
+					Some(key) => format!("{}[{}]", upvalue(b), key),
+					None => upvalue(b),
+				},
+				Opcode::SetTabUp => {
+					let mut s = upvalue(a as u32);
+					if let Some(key) = rk(b) {
+						write!(s, "[{}]", key).unwrap();
+					}
+					if let Some(value) = rk(c) {
+						write!(s, " {}", value).unwrap();
+					}
+					s
+				}
+				Opcode::GetTable | Opcode::Self_ => rk(c).unwrap_or_default(),
+				Opcode::SetList if c == 0 => match self.code.get(pc + 1).and_then(|&next| bytecode::decode(next)) {
+					Some(DecodedInstruction::Ax(Opcode::ExtraArg, ax)) => ax.to_string(),
+					_ => String::new(),
+				},
+				Opcode::SetList => c.to_string(),
+				_ if op.arg_modes() == (ArgMode::Constant, ArgMode::Constant) => {
+					match (rk(b), rk(c)) {
+						(None, None) => String::new(),
+						(b, c) => format!("{} {}", b.unwrap_or("-".to_owned()), c.unwrap_or("-".to_owned())),
+					}
+				}
+				_ => String::new(),
+			},
+			_ => String::new(),
+		}
+	}
+}
+
+fn disassemble(function: &Function, depth: usize, out: &mut String) -> fmt::Result {
+	let indent = "\t".repeat(depth + 1);
+	try!(writeln!(out, "{}function", &indent[1..]));
+	if let Some(ref source) = function.source {
+		try!(write!(out, "{}source ", indent));
+		try!(write_quoted(out, source));
+		try!(writeln!(out, ""));
+	}
+	try!(writeln!(out, "{}lines {} {}", indent, function.line_start, function.line_end));
+	try!(writeln!(out, "{}params {}", indent, function.num_params));
+	try!(writeln!(out, "{}vararg {}", indent, function.is_vararg));
+	try!(writeln!(out, "{}maxstack {}", indent, function.max_stack_size));
+	for upvalue in &function.upvalues {
+		try!(match *upvalue {
+			Upvalue::Stack(idx) => writeln!(out, "{}upvalue stack {}", indent, idx),
+			Upvalue::Outer(idx) => writeln!(out, "{}upvalue outer {}", indent, idx),
+		});
+	}
+	for constant in &function.constants {
+		try!(write!(out, "{}constant ", indent));
+		try!(match *constant {
+			Constant::Nil => write!(out, "nil"),
+			Constant::Boolean(b) => write!(out, "{}", b),
+			Constant::Float(n) if n.is_finite() => write!(out, "float {:?}", n),
+			Constant::Float(n) => write!(out, "floatbits {:#018x}", n.to_bits()),
+			Constant::RawFloat(ref bytes) => {
+				try!(write!(out, "rawfloat "));
+				for byte in bytes {
+					try!(write!(out, "{:02x}", byte));
+				}
+				Ok(())
+			}
+			Constant::Int(i) => write!(out, "int {}", i),
+			Constant::ShortString(ref s) => write!(out, "string ").and_then(|_| write_quoted(out, s)),
+			Constant::LongString(ref s) => write!(out, "longstring ").and_then(|_| write_quoted(out, s)),
+		});
+		try!(writeln!(out, ""));
+	}
+	for (pc, &raw) in function.code.iter().enumerate() {
+		let line = function.annotate_instruction(pc);
+		if asm::parse_instruction(&line) == Ok(raw) {
+			try!(writeln!(out, "{}{}", indent, line));
+		} else {
+			try!(writeln!(out, "{}.word {:#010x}", indent, raw));
+		}
+	}
+	for proto in &function.protos {
+		try!(disassemble(proto, depth + 1, out));
+	}
+	writeln!(out, "{}end", &indent[1..])
+}
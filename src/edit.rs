@@ -0,0 +1,764 @@
+//! In-place transformations of functions.
+
+use std::{cmp, error, fmt, mem};
+use std::collections::HashMap;
+use std::ops::Range;
+
+use bytecode::{self, ArgMode, DecodedInstruction, Opcode, RK, MAXARG_BX, MAXINDEXRK, MIN_SBX, MAX_SBX};
+use super::{Int, Instruction, Chunk, Constant, Debug, Header, Function, LuaString, Upvalue, Version};
+
+/// The longest string Lua stores as a short string.
+const MAX_SHORT_LEN: usize = 40;
+
+/// An error encountered while editing a function.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EditError {
+	/// The function has no nested function at the given index.
+	NoSuchProto(usize),
+	/// A nested function could not be removed because a `Closure`
+	/// instruction still refers to it.
+	ProtoInUse {
+		/// The index of the nested function.
+		index: usize,
+		/// The program counter of the first `Closure` referring to it.
+		pc: usize,
+	},
+	/// Instructions could not be removed because a jump targets one of them.
+	JumpIntoRemoved {
+		/// The program counter of the jump.
+		from: usize,
+		/// The program counter of the removed instruction it targets.
+		to: usize,
+	},
+	/// A jump's offset to its target no longer fits in `sBx`.
+	JumpOutOfRange {
+		/// The program counter of the jump, after editing.
+		pc: usize,
+	},
+	/// The instruction at the given program counter is missing or has no
+	/// `sBx` jump offset.
+	NotAJump(usize),
+	/// The program counter is past the end of the code.
+	NoSuchInstruction(usize),
+	/// The edit would separate the instruction at the given program counter
+	/// from the next one, which it skips or takes an argument from.
+	SplitsPair(usize),
+	/// The chunk's header is for a version of Lua the edit doesn't support.
+	UnsupportedVersion(Version),
+	/// A constant is a float of a size which can't be converted.
+	RawFloat,
+}
+
+impl fmt::Display for EditError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			EditError::NoSuchProto(index) => write!(f, "no nested function at index {}", index),
+			EditError::ProtoInUse { index, pc } => write!(f,
+				"nested function {} is still used by the closure at {}", index, pc),
+			EditError::JumpIntoRemoved { from, to } => write!(f,
+				"jump at {} targets removed instruction {}", from, to),
+			EditError::JumpOutOfRange { pc } => write!(f, "jump at {} is out of range", pc),
+			EditError::NotAJump(pc) => write!(f, "no jump at {}", pc),
+			EditError::NoSuchInstruction(pc) => write!(f, "no instruction at {}", pc),
+			EditError::SplitsPair(pc) => write!(f,
+				"instruction at {} would be separated from the next, which it depends on", pc),
+			EditError::UnsupportedVersion(version) => write!(f, "unsupported version {:?}", version),
+			EditError::RawFloat => write!(f, "float constant of unconvertible size"),
+		}
+	}
+}
+
+impl error::Error for EditError {
+	fn description(&self) -> &str {
+		match *self {
+			EditError::NoSuchProto(..) => "no such nested function",
+			EditError::ProtoInUse { .. } => "nested function in use",
+			EditError::JumpIntoRemoved { .. } => "jump into removed instructions",
+			EditError::JumpOutOfRange { .. } => "jump out of range",
+			EditError::NotAJump(..) => "not a jump",
+			EditError::NoSuchInstruction(..) => "no such instruction",
+			EditError::SplitsPair(..) => "instruction pair split",
+			EditError::UnsupportedVersion(..) => "unsupported version",
+			EditError::RawFloat => "raw float constant",
+		}
+	}
+}
+
+/// An error encountered while extracting a nested function.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ExtractError {
+	/// The function has no nested function at the given index.
+	NoSuchProto(usize),
+	/// The nested function captures a local variable of its parent.
+	CapturesLocal {
+		/// The index of the nested function's upvalue.
+		upvalue: usize,
+		/// The upvalue's name, if known.
+		name: Option<LuaString>,
+	},
+	/// The nested function captures an upvalue of its parent other than
+	/// `_ENV`.
+	CapturesUpvalue {
+		/// The index of the nested function's upvalue.
+		upvalue: usize,
+		/// The upvalue's name, if known.
+		name: Option<LuaString>,
+	},
+}
+
+impl fmt::Display for ExtractError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let (what, upvalue, name) = match *self {
+			ExtractError::NoSuchProto(index) => return write!(f, "no nested function at index {}", index),
+			ExtractError::CapturesLocal { upvalue, ref name } => ("local", upvalue, name),
+			ExtractError::CapturesUpvalue { upvalue, ref name } => ("upvalue", upvalue, name),
+		};
+		try!(write!(f, "upvalue {}", upvalue));
+		if let Some(ref name) = *name {
+			try!(write!(f, " ({})", name));
+		}
+		write!(f, " captures a {} of the parent function", what)
+	}
+}
+
+impl error::Error for ExtractError {
+	fn description(&self) -> &str {
+		match *self {
+			ExtractError::NoSuchProto(..) => "no such nested function",
+			ExtractError::CapturesLocal { .. } => "nested function captures a local",
+			ExtractError::CapturesUpvalue { .. } => "nested function captures an upvalue",
+		}
+	}
+}
+
+/// A constant's identity for deduplication, as returned by
+/// `Constant::dedup_key`.
+///
+/// Keys are ordered first by type, in the order of the variants, and then
+/// by value. Floats are ordered by their bits rather than numerically, so
+/// that the order is total.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ConstantKey<'a> {
+	/// The key of `nil`.
+	Nil,
+	/// The key of a boolean.
+	Boolean(bool),
+	/// The key of a float, by its bits.
+	Float(u64),
+	/// The key of a float of a size other than that of `Number`.
+	RawFloat(&'a [u8]),
+	/// The key of an integer.
+	Int(i64),
+	/// The key of a short or long string.
+	String(&'a [u8]),
+}
+
+impl Constant {
+	/// A hashable key under which two constants are equal exactly when
+	/// they may share a slot in a constant table.
+	///
+	/// Integers and floats never share a key, so `1` and `1.0` stay
+	/// distinct as they do in Lua. Floats are compared by their bits, so
+	/// `0.0` and `-0.0` are distinct while a NaN equals itself. Short and
+	/// long strings with the same contents share a key.
+	pub fn dedup_key(&self) -> ConstantKey<'_> {
+		match *self {
+			Constant::Nil => ConstantKey::Nil,
+			Constant::Boolean(b) => ConstantKey::Boolean(b),
+			Constant::Float(n) => ConstantKey::Float(n.to_bits()),
+			Constant::RawFloat(ref bytes) => ConstantKey::RawFloat(bytes),
+			Constant::Int(n) => ConstantKey::Int(n),
+			Constant::ShortString(ref s) | Constant::LongString(ref s) => ConstantKey::String(s),
+		}
+	}
+}
+
+impl Chunk {
+	/// Rewrite the chunk into a canonical form.
+	///
+	/// The header becomes `Header::default()`: little-endian with a 4-byte
+	/// int and instruction and an 8-byte `size_t`, `Integer`, and `Number`.
+	/// In every function, constants with the same `Constant::dedup_key` are
+	/// merged into their first occurrence, and strings are tagged short if
+	/// they are at most 40 bytes long and long otherwise, matching Lua's own
+	/// choice.
+	///
+	/// Only Lua 5.3 chunks can be canonicalized, and none of their constants
+	/// may be a `Constant::RawFloat`, whose bytes would not match the new
+	/// header. The chunk is left unchanged if either is found.
+	pub fn canonicalize(&mut self) -> Result<(), EditError> {
+		if self.header.version != Version::Lua53 {
+			return Err(EditError::UnsupportedVersion(self.header.version));
+		}
+		for (_, function) in self.main.iter_protos() {
+			for constant in &function.constants {
+				if let Constant::RawFloat(..) = *constant {
+					return Err(EditError::RawFloat);
+				}
+			}
+		}
+		self.header = Header::default();
+		canonicalize(&mut self.main);
+		Ok(())
+	}
+
+	/// Remove all debug information from the chunk, as `luac -s` does.
+	pub fn strip_debug(&mut self) {
+		self.main.strip_debug();
+	}
+
+	/// A copy of the chunk with all debug information removed.
+	pub fn without_debug(&self) -> Chunk {
+		let mut chunk = self.clone();
+		chunk.strip_debug();
+		chunk
+	}
+}
+
+fn canonicalize(function: &mut Function) {
+	let mut map = Vec::with_capacity(function.constants.len());
+	let mut constants = Vec::new();
+	{
+		let mut seen = HashMap::new();
+		for constant in &function.constants {
+			let index = *seen.entry(constant.dedup_key()).or_insert_with(|| {
+				constants.push(match *constant {
+					Constant::ShortString(ref s) | Constant::LongString(ref s) if s.len() > MAX_SHORT_LEN =>
+						Constant::LongString(s.clone()),
+					Constant::ShortString(ref s) | Constant::LongString(ref s) =>
+						Constant::ShortString(s.clone()),
+					ref other => other.clone(),
+				});
+				constants.len() - 1
+			});
+			map.push(index);
+		}
+	}
+	function.constants = constants;
+	remap_constants(function, &map);
+
+	for proto in &mut function.protos {
+		canonicalize(proto);
+	}
+}
+
+fn map_constants_recursive<F: FnMut(&mut Constant)>(function: &mut Function, f: &mut F) {
+	for constant in &mut function.constants {
+		f(constant);
+	}
+	for proto in &mut function.protos {
+		map_constants_recursive(proto, f);
+	}
+}
+
+fn sort_constants(function: &mut Function) {
+	let len = function.constants.len();
+	// Constants used as RK parameters, and then those loaded by LoadK, are
+	// kept in front when the table is too long for them to go anywhere.
+	let mut rank = vec![2; len];
+	for &ins in &function.code {
+		match bytecode::decode(ins) {
+			Some(DecodedInstruction::ABx(Opcode::LoadK, _, bx)) if len > MAXARG_BX as usize + 1 => {
+				if let Some(rank) = rank.get_mut(bx as usize) {
+					*rank = cmp::min(*rank, 1);
+				}
+			}
+			Some(DecodedInstruction::ABC(op, _, b, c)) if len > MAXINDEXRK as usize + 1 => {
+				let (b_mode, c_mode) = op.arg_modes();
+				for &(mode, value) in &[(b_mode, b), (c_mode, c)] {
+					if let (ArgMode::Constant, RK::K(k)) = (mode, RK::decode(value)) {
+						if let Some(rank) = rank.get_mut(k as usize) {
+							*rank = 0;
+						}
+					}
+				}
+			}
+			_ => {}
+		}
+	}
+
+	let mut order: Vec<usize> = (0..len).collect();
+	{
+		let constants = &function.constants;
+		order.sort_by_key(|&i| (rank[i], constants[i].dedup_key(), constants[i].type_tag(Version::Lua53)));
+	}
+	let mut map = vec![0; len];
+	for (new, &old) in order.iter().enumerate() {
+		map[old] = new;
+	}
+	let mut constants: Vec<Option<Constant>> = mem::take(&mut function.constants)
+		.into_iter().map(Some).collect();
+	function.constants = order.iter().map(|&old| constants[old].take().unwrap()).collect();
+	remap_constants(function, &map);
+}
+
+/// Rewrite every constant reference in `function`'s code so that index `i`
+/// becomes `map[i]`.
+///
+/// References outside of `map` are left alone. Panics if an `RK` parameter
+/// would be moved past `MAXINDEXRK`.
+fn remap_constants(function: &mut Function, map: &[usize]) {
+	let remap = |k: u32| map.get(k as usize).map_or(k, |&new| new as u32);
+	let mut pc = 0;
+	while pc < function.code.len() {
+		let ins = match bytecode::decode(function.code[pc]) {
+			Some(ins) => ins,
+			None => { pc += 1; continue }
+		};
+		let rk = |value: u32| match RK::decode(value) {
+			RK::K(k) => {
+				let new = remap(k as u32);
+				assert!(new <= MAXINDEXRK, "constant {} cannot be an RK parameter", new);
+				RK::K(new as u8).encode()
+			}
+			RK::R(_) => value,
+		};
+		function.code[pc] = match ins {
+			DecodedInstruction::ABx(Opcode::LoadK, a, bx) =>
+				bytecode::encode_bx(Opcode::LoadK, a, remap(bx)),
+			DecodedInstruction::ABx(Opcode::LoadKX, ..) => {
+				// The constant index is in the following ExtraArg.
+				if let Some(DecodedInstruction::Ax(Opcode::ExtraArg, ax)) =
+					function.code.get(pc + 1).and_then(|&i| bytecode::decode(i))
+				{
+					function.code[pc + 1] = bytecode::encode_ax(Opcode::ExtraArg, remap(ax));
+				}
+				pc += 1;
+				continue;
+			}
+			DecodedInstruction::ABC(op, a, b, c) => {
+				let (b_mode, c_mode) = op.arg_modes();
+				let b = if b_mode == ArgMode::Constant { rk(b) } else { b };
+				let c = if c_mode == ArgMode::Constant { rk(c) } else { c };
+				bytecode::encode(op, a, b, c)
+			}
+			other => other.encode(),
+		};
+		pc += 1;
+	}
+}
+
+impl Function {
+	/// Remove the debug information and source of this function and all
+	/// nested functions, as `luac -s` does.
+	pub fn strip_debug(&mut self) {
+		self.source = None;
+		self.debug = Debug::none();
+		for proto in &mut self.protos {
+			proto.strip_debug();
+		}
+	}
+
+	/// Apply `f` to each constant in this function.
+	///
+	/// The constant table keeps its length, so no instructions need to be
+	/// updated.
+	pub fn map_constants<F: FnMut(&mut Constant)>(&mut self, mut f: F) {
+		for constant in &mut self.constants {
+			f(constant);
+		}
+	}
+
+	/// Apply `f` to each constant in this function and all nested
+	/// functions, parents before children.
+	pub fn map_constants_recursive<F: FnMut(&mut Constant)>(&mut self, mut f: F) {
+		map_constants_recursive(self, &mut f);
+	}
+
+	/// Reorder this function's constant table into a canonical order, by
+	/// type and then value as `ConstantKey` is ordered, and update the
+	/// instructions which refer to it.
+	///
+	/// Functions which differ only in the order of their constants become
+	/// identical, though duplicates are kept; `Chunk::canonicalize` first
+	/// to merge them. If the table is longer than `RK` parameters can
+	/// reach, the constants they use are sorted ahead of the rest so that
+	/// they stay reachable, and likewise for `LoadK` beyond its reach.
+	pub fn sort_constants(&mut self) {
+		sort_constants(self);
+	}
+
+	/// Reorder the constant tables of this function and all nested
+	/// functions, as `sort_constants` does.
+	pub fn sort_constants_recursive(&mut self) {
+		sort_constants(self);
+		for proto in &mut self.protos {
+			proto.sort_constants_recursive();
+		}
+	}
+
+	/// Remove the local variable and upvalue names of this function and
+	/// all nested functions, keeping line info and sources so that errors
+	/// still report their locations.
+	pub fn strip_names(&mut self) {
+		self.debug.localvars.clear();
+		self.debug.upvalues.clear();
+		for proto in &mut self.protos {
+			proto.strip_names();
+		}
+	}
+
+	/// A copy of this function with its debug information removed, as by
+	/// `strip_debug`.
+	pub fn without_debug(&self) -> Function {
+		let mut function = self.clone();
+		function.strip_debug();
+		function
+	}
+
+	/// Add `delta` to every line number in this function and all nested
+	/// functions.
+	///
+	/// Results saturate at zero and at the largest `Int` rather than
+	/// wrapping. A `line_start` and `line_end` of zero, which mark the main
+	/// function, are left alone.
+	pub fn shift_lines(&mut self, delta: i64) {
+		let shift = |line: Int| {
+			let line = (line as i64).saturating_add(delta);
+			cmp::max(0, cmp::min(line, Int::MAX as i64)) as Int
+		};
+		if self.line_start != 0 || self.line_end != 0 {
+			self.line_start = shift(self.line_start);
+			self.line_end = shift(self.line_end);
+		}
+		for line in &mut self.debug.lineinfo {
+			*line = shift(*line);
+		}
+		for proto in &mut self.protos {
+			proto.shift_lines(delta);
+		}
+	}
+
+	/// Remove and return the nested function at `index`.
+	///
+	/// Fails if any `Closure` instruction still creates it. `Closure`
+	/// instructions referring to later nested functions are renumbered to
+	/// match their new indices.
+	pub fn remove_proto(&mut self, index: usize) -> Result<Function, EditError> {
+		if index >= self.protos.len() {
+			return Err(EditError::NoSuchProto(index));
+		}
+		if let Some(&(pc, _)) = self.closure_edges().iter().find(|&&(_, proto)| proto == index) {
+			return Err(EditError::ProtoInUse { index, pc });
+		}
+		let index_bx = index as u32;
+		for ins in &mut self.code {
+			if let Some(DecodedInstruction::ABx(Opcode::Closure, a, bx)) = bytecode::decode(*ins) {
+				if bx > index_bx {
+					*ins = bytecode::encode_bx(Opcode::Closure, a, bx - 1);
+				}
+			}
+		}
+		Ok(self.protos.remove(index))
+	}
+
+	/// Remove the nested functions which no reachable `Closure` creates,
+	/// in this function and recursively in those that remain.
+	///
+	/// Reachability follows the function's basic blocks from its entry.
+	/// `Closure` instructions for the remaining functions are renumbered,
+	/// and unreachable ones for removed functions become `LoadNil` of the
+	/// same register so that no index is left dangling. `Closure`
+	/// instructions with an out of range index are left alone.
+	pub fn prune_dead_protos(&mut self) {
+		let blocks = self.basic_blocks();
+		let mut reachable = vec![false; self.code.len()];
+		let mut visited = vec![false; blocks.len()];
+		let mut stack = if blocks.is_empty() { vec![] } else { vec![0] };
+		while let Some(block) = stack.pop() {
+			if visited[block] {
+				continue;
+			}
+			visited[block] = true;
+			for flag in &mut reachable[blocks[block].start..blocks[block].end] {
+				*flag = true;
+			}
+			stack.extend(&blocks[block].successors);
+		}
+
+		let mut live = vec![false; self.protos.len()];
+		for (pc, index) in self.closure_edges() {
+			if reachable[pc] && index < live.len() {
+				live[index] = true;
+			}
+		}
+		let mut next = 0;
+		let renumbered: Vec<_> = live.iter().map(|&live| if live {
+			next += 1;
+			Some(next - 1)
+		} else {
+			None
+		}).collect();
+
+		for ins in &mut self.code {
+			if let Some(DecodedInstruction::ABx(Opcode::Closure, a, bx)) = bytecode::decode(*ins) {
+				match renumbered.get(bx as usize) {
+					Some(&Some(new)) => *ins = bytecode::encode_bx(Opcode::Closure, a, new as u32),
+					Some(&None) => *ins = bytecode::encode(Opcode::LoadNil, a, 0, 0),
+					None => {}
+				}
+			}
+		}
+		let protos = mem::take(&mut self.protos);
+		self.protos = protos.into_iter().zip(live)
+			.filter(|&(_, live)| live)
+			.map(|(proto, _)| proto)
+			.collect();
+		for proto in &mut self.protos {
+			proto.prune_dead_protos();
+		}
+	}
+
+	/// Copy the nested function at `index` into a standalone main function.
+	///
+	/// The only upvalue a standalone function can have is `_ENV`, so the
+	/// nested function may only capture its parent's `_ENV`, which is
+	/// recognized by the upvalue names in either function's debug info.
+	/// If it does, the copy's upvalue refers to the environment it is
+	/// loaded with, as a main function's does. A copy with no source of its
+	/// own takes its parent's.
+	pub fn extract_child(&self, index: usize) -> Result<Function, ExtractError> {
+		let mut child = match self.protos.get(index) {
+			Some(child) => child.clone(),
+			None => return Err(ExtractError::NoSuchProto(index)),
+		};
+		for (i, upvalue) in child.upvalues.iter().enumerate() {
+			let name = child.debug.upvalues.get(i).cloned();
+			match *upvalue {
+				Upvalue::Stack(_) => return Err(ExtractError::CapturesLocal { upvalue: i, name }),
+				Upvalue::Outer(k) => {
+					let name = name.or_else(|| self.debug.upvalues.get(k as usize).cloned());
+					match name {
+						Some(ref name) if *name == "_ENV" => {}
+						_ => return Err(ExtractError::CapturesUpvalue { upvalue: i, name }),
+					}
+				}
+			}
+		}
+		// Lua merges duplicate upvalues, so `_ENV` can only be the first.
+		if !child.upvalues.is_empty() {
+			child.upvalues = vec![Upvalue::Stack(0)];
+		}
+		if child.source.is_none() {
+			child.source = self.source.clone();
+		}
+		Ok(child)
+	}
+
+	/// Insert an instruction at `pc`, shifting later instructions along.
+	///
+	/// Jumps and local variable ranges are updated so that they still
+	/// refer to the same instructions, except that those which referred to
+	/// `pc` itself now refer to the inserted instruction, so that it runs
+	/// whenever the instruction it was inserted before would have. The
+	/// inserted instruction takes the line of that instruction, if there is
+	/// line info.
+	///
+	/// Fails without changing anything if `pc` is past the end of the code,
+	/// if the instruction before `pc` skips the next or takes its argument
+	/// from it, or if a jump's offset would no longer fit.
+	pub fn insert_instruction(&mut self, pc: usize, ins: Instruction) -> Result<(), EditError> {
+		let len = self.code.len();
+		if pc > len {
+			return Err(EditError::NoSuchInstruction(pc));
+		}
+		if pc > 0 && binds_next(self.code[pc - 1]) {
+			return Err(EditError::SplitsPair(pc - 1));
+		}
+		let moved = |old: usize| if old > pc { old + 1 } else { old };
+
+		let mut jumps = Vec::new();
+		for (old, decoded) in self.decoded_instructions() {
+			if let Some(DecodedInstruction::AsBx(op, a, sbx)) = decoded {
+				let from = if old >= pc { old + 1 } else { old };
+				let target = old as isize + 1 + sbx as isize;
+				let to = if target >= 0 { moved(target as usize) as isize } else { target };
+				let sbx = to - from as isize - 1;
+				if sbx < MIN_SBX as isize || sbx > MAX_SBX as isize {
+					return Err(EditError::JumpOutOfRange { pc: from });
+				}
+				jumps.push((old, bytecode::encode_sbx(op, a, sbx as i32)));
+			}
+		}
+		for (old, jump) in jumps {
+			self.code[old] = jump;
+		}
+
+		self.code.insert(pc, ins);
+		if self.debug.lineinfo.len() == len {
+			let line = self.debug.lineinfo.get(pc).or(self.debug.lineinfo.last()).cloned().unwrap_or(self.line_start);
+			self.debug.lineinfo.insert(pc, line);
+		}
+		for var in &mut self.debug.localvars {
+			if var.start_pc >= 0 {
+				var.start_pc = moved(var.start_pc as usize) as Int;
+			}
+			if var.end_pc >= 0 {
+				var.end_pc = moved(var.end_pc as usize) as Int;
+			}
+		}
+		Ok(())
+	}
+
+	/// Remove the instructions in `range`, shifting later instructions back.
+	///
+	/// Jumps, line info, and local variable ranges are updated so that they
+	/// still refer to the same instructions. Fails without changing
+	/// anything if an instruction outside the range jumps or skips to one
+	/// inside it, or if the instruction before the range skips the first
+	/// one in it or takes its argument from it, or if the range is reversed
+	/// or extends past the end of the code.
+	pub fn remove_instructions(&mut self, range: Range<usize>) -> Result<(), EditError> {
+		let len = self.code.len();
+		if range.end > len {
+			return Err(EditError::NoSuchInstruction(len));
+		}
+		if range.start > range.end {
+			return Err(EditError::NoSuchInstruction(range.start));
+		}
+		if range.start > 0 && range.start < range.end && binds_next(self.code[range.start - 1]) {
+			return Err(EditError::SplitsPair(range.start - 1));
+		}
+		for (from, to) in self.jump_targets() {
+			if (from < range.start || from >= range.end) && to >= range.start && to < range.end {
+				return Err(EditError::JumpIntoRemoved { from, to });
+			}
+		}
+		let removed: Vec<bool> = (0..len).map(|pc| pc >= range.start && pc < range.end).collect();
+		remove_marked(self, &removed);
+		Ok(())
+	}
+
+	/// Point the jump at `pc` to `target`, which is an absolute program
+	/// counter as returned by `DecodedInstruction::jump_target`.
+	///
+	/// Fails if the instruction has no `sBx` offset or the offset to
+	/// `target` doesn't fit in one. The target isn't required to be within
+	/// the code.
+	pub fn set_jump_target(&mut self, pc: usize, target: usize) -> Result<(), EditError> {
+		let (op, a) = match self.code.get(pc).and_then(|&ins| bytecode::decode(ins)) {
+			Some(DecodedInstruction::AsBx(op, a, _)) => (op, a),
+			_ => return Err(EditError::NotAJump(pc)),
+		};
+		let sbx = target as i64 - pc as i64 - 1;
+		if sbx < MIN_SBX as i64 || sbx > MAX_SBX as i64 {
+			return Err(EditError::JumpOutOfRange { pc });
+		}
+		self.code[pc] = bytecode::encode_sbx(op, a, sbx as i32);
+		Ok(())
+	}
+
+	/// Remove redundant instructions.
+	///
+	/// Self-moves (`Move` with `A` equal to `B`) are removed, and a
+	/// `LoadNil` whose range touches that of the `LoadNil` before it is
+	/// merged into it, unless something jumps to it. Instructions which may
+	/// be skipped by the one before them are left alone. Jumps, line info,
+	/// and local variable ranges are updated to match.
+	pub fn peephole(&mut self) {
+		let len = self.code.len();
+		let mut targeted = vec![false; len + 1];
+		for (_, to) in self.jump_targets() {
+			if to <= len {
+				targeted[to] = true;
+			}
+		}
+
+		let mut removed = vec![false; len];
+		// The last kept LoadNil, as (pc, first register, last register).
+		let mut last_nil: Option<(usize, u32, u32)> = None;
+		let mut skippable = false;
+		for pc in 0..len {
+			let ins = bytecode::decode(self.code[pc]);
+			let mut nil = None;
+			match ins {
+				Some(DecodedInstruction::ABC(Opcode::Move, a, b, _)) if !skippable && a as u32 == b => {
+					removed[pc] = true;
+					// Jumps here will land on the next instruction instead.
+					if targeted[pc] {
+						targeted[pc + 1] = true;
+					}
+				}
+				Some(DecodedInstruction::ABC(Opcode::LoadNil, a, b, _)) if !skippable => {
+					let (from, to) = (a as u32, a as u32 + b);
+					nil = Some((pc, from, to));
+					if let Some((prev, prev_from, prev_to)) = last_nil {
+						if !targeted[pc] && from <= prev_to + 1 && prev_from <= to + 1 {
+							let (from, to) = (cmp::min(from, prev_from), cmp::max(to, prev_to));
+							self.code[prev] = bytecode::encode(Opcode::LoadNil, from as u8, to - from, 0);
+							removed[pc] = true;
+							nil = Some((prev, from, to));
+						}
+					}
+				}
+				_ => {}
+			}
+			// A merge is only valid between adjacent kept instructions.
+			if !removed[pc] || nil.is_some() {
+				last_nil = nil;
+			}
+			skippable = binds_next(self.code[pc]);
+		}
+		remove_marked(self, &removed);
+	}
+}
+
+/// Whether an instruction must stay immediately before the next one.
+///
+/// Tests and `LoadBool` with a nonzero `C` skip the next instruction, and
+/// `LoadKX` takes its argument from the `ExtraArg` after it.
+fn binds_next(ins: Instruction) -> bool {
+	match bytecode::decode(ins) {
+		Some(DecodedInstruction::ABC(Opcode::LoadBool, _, _, c)) => c != 0,
+		Some(DecodedInstruction::ABC(op, ..)) => op.is_test(),
+		Some(DecodedInstruction::ABx(Opcode::LoadKX, ..)) => true,
+		_ => false,
+	}
+}
+
+/// Remove the instructions at the marked program counters, keeping jumps,
+/// line info, and local variable ranges pointing at the same code.
+///
+/// A jump to a removed instruction is redirected to the one following it.
+fn remove_marked(function: &mut Function, removed: &[bool]) {
+	let len = function.code.len();
+	// The new program counter of each old one, including the end.
+	let mut moved = Vec::with_capacity(len + 1);
+	let mut count = 0;
+	for (pc, &gone) in removed[..len].iter().enumerate() {
+		moved.push(pc - count);
+		if gone {
+			count += 1;
+		}
+	}
+	moved.push(len - count);
+	if count == 0 {
+		return;
+	}
+
+	for pc in 0..len {
+		if removed[pc] {
+			continue;
+		}
+		if let Some(DecodedInstruction::AsBx(op, a, sbx)) = bytecode::decode(function.code[pc]) {
+			let target = pc as isize + 1 + sbx as isize;
+			if target >= 0 && target as usize <= len {
+				let sbx = moved[target as usize] as isize - moved[pc] as isize - 1;
+				function.code[pc] = bytecode::encode_sbx(op, a, sbx as i32);
+			}
+		}
+	}
+
+	let keep = |pc: usize| pc >= len || !removed[pc];
+	function.code = function.code.iter().enumerate()
+		.filter(|&(pc, _)| keep(pc)).map(|(_, &ins)| ins).collect();
+	if function.debug.lineinfo.len() == len {
+		function.debug.lineinfo = function.debug.lineinfo.iter().enumerate()
+			.filter(|&(pc, _)| keep(pc)).map(|(_, &line)| line).collect();
+	}
+	let relocate = |pc: Int| if pc >= 0 && pc as usize <= len { moved[pc as usize] as Int } else { pc };
+	for var in &mut function.debug.localvars {
+		var.start_pc = relocate(var.start_pc);
+		var.end_pc = relocate(var.end_pc);
+	}
+}
@@ -0,0 +1,62 @@
+//! Summaries of where a chunk likely came from.
+
+use super::{Chunk, Header, SourceName, Version};
+
+/// A best-effort guess at how a chunk was produced, from its main
+/// function's source.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Producer {
+	/// `luac` combining several files, which names the main function
+	/// `=(luac)`.
+	LuacCombined,
+	/// `luac` reading from standard input, named `=stdin`.
+	LuacStdin,
+	/// A file compiled by `luac` or loaded with `loadfile` and dumped.
+	File,
+	/// A string loaded with `load` and dumped, either with a `=name` or
+	/// with the source text itself as its name.
+	Load,
+	/// The source was stripped, so nothing can be told.
+	Unknown,
+}
+
+/// A summary of a chunk's format and origin, for cataloging.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ChunkFingerprint {
+	/// The Lua version of the bytecode.
+	pub version: Version,
+	/// The byte order and sizes of the bytecode.
+	pub header: Header,
+	/// Whether the sizes differ from those of stock Lua built for common
+	/// 32- and 64-bit platforms, suggesting a customized build.
+	pub custom_sizes: bool,
+	/// Whether every function in the chunk is stripped of debug
+	/// information.
+	pub stripped: bool,
+	/// The likely producer of the chunk.
+	pub producer: Producer,
+}
+
+impl Chunk {
+	/// Summarize the chunk's version, header, debug information, and likely
+	/// producer.
+	pub fn fingerprint(&self) -> ChunkFingerprint {
+		let header = self.header;
+		let producer = match self.main.source_name() {
+			Some(SourceName::Name(ref name)) if name == "(luac)" => Producer::LuacCombined,
+			Some(SourceName::Name(ref name)) if name == "stdin" => Producer::LuacStdin,
+			Some(SourceName::File(_)) => Producer::File,
+			Some(SourceName::Name(_)) | Some(SourceName::Literal(_)) => Producer::Load,
+			None => Producer::Unknown,
+		};
+		ChunkFingerprint {
+			version: header.version,
+			header,
+			custom_sizes: !(header.int_size == 4 && header.instruction_size == 4 &&
+				(header.size_t_size == 4 || header.size_t_size == 8) &&
+				header.integer_size == 8 && header.number_size == 8),
+			stripped: self.main.iter_protos().all(|(_, function)| function.is_stripped()),
+			producer,
+		}
+	}
+}
@@ -0,0 +1,189 @@
+//! A JSON interchange format for chunks.
+//!
+//! Requires the `json` feature.
+
+use std::{error, fmt};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::{self, Visitor, SeqAccess};
+use serde_json;
+
+use super::{Chunk, LuaString, Number};
+
+/// The version of the JSON format written by `to_json`, which is changed
+/// whenever the format changes incompatibly.
+pub const JSON_SCHEMA: u32 = 2;
+
+/// A chunk along with the version of the JSON format it is written in.
+///
+/// The version is written as the top-level `lua_kit_schema` field, so that
+/// other tools can detect documents in a format they don't understand.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChunkDocument {
+	/// The version of the JSON format.
+	#[serde(rename = "lua_kit_schema")]
+	pub schema: u32,
+	/// The chunk.
+	pub chunk: Chunk,
+}
+
+impl ChunkDocument {
+	/// A document holding `chunk` in the current format.
+	pub fn new(chunk: Chunk) -> ChunkDocument {
+		ChunkDocument { schema: JSON_SCHEMA, chunk }
+	}
+}
+
+/// An error encountered while converting a chunk to or from JSON.
+#[derive(Debug)]
+pub enum JsonError {
+	/// The JSON was malformed or didn't describe a chunk.
+	Json(serde_json::Error),
+	/// The document is in a version of the format other than `JSON_SCHEMA`.
+	Schema(u32),
+}
+
+impl fmt::Display for JsonError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			JsonError::Json(ref err) => err.fmt(f),
+			JsonError::Schema(schema) => write!(f,
+				"unsupported schema version {}, expected {}", schema, JSON_SCHEMA),
+		}
+	}
+}
+
+impl error::Error for JsonError {
+	fn description(&self) -> &str {
+		match *self {
+			JsonError::Json(..) => "invalid JSON",
+			JsonError::Schema(..) => "unsupported schema version",
+		}
+	}
+
+	fn cause(&self) -> Option<&error::Error> {
+		match *self {
+			JsonError::Json(ref err) => Some(err),
+			JsonError::Schema(..) => None,
+		}
+	}
+}
+
+impl From<serde_json::Error> for JsonError {
+	fn from(err: serde_json::Error) -> JsonError {
+		JsonError::Json(err)
+	}
+}
+
+/// Convert a chunk to a JSON `ChunkDocument`.
+pub fn to_json(chunk: &Chunk) -> Result<String, JsonError> {
+	#[derive(Serialize)]
+	struct Borrowed<'a> {
+		#[serde(rename = "lua_kit_schema")]
+		schema: u32,
+		chunk: &'a Chunk,
+	}
+	Ok(try!(serde_json::to_string(&Borrowed { schema: JSON_SCHEMA, chunk })))
+}
+
+/// Read a chunk from a JSON `ChunkDocument`, failing if it is in another
+/// version of the format.
+pub fn from_json(json: &str) -> Result<Chunk, JsonError> {
+	let document: ChunkDocument = try!(serde_json::from_str(json));
+	if document.schema != JSON_SCHEMA {
+		return Err(JsonError::Schema(document.schema));
+	}
+	Ok(document.chunk)
+}
+
+/// Strings are written as JSON strings when they are valid UTF-8, and
+/// otherwise as arrays of bytes.
+impl Serialize for LuaString {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		match self.as_str() {
+			Some(s) => serializer.serialize_str(s),
+			None => self.as_bytes().serialize(serializer),
+		}
+	}
+}
+
+impl<'de> Deserialize<'de> for LuaString {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<LuaString, D::Error> {
+		struct StringVisitor;
+
+		impl<'de> Visitor<'de> for StringVisitor {
+			type Value = LuaString;
+
+			fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+				f.write_str("a string or an array of bytes")
+			}
+
+			fn visit_str<E: de::Error>(self, s: &str) -> Result<LuaString, E> {
+				Ok(s.into())
+			}
+
+			fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<LuaString, A::Error> {
+				let mut bytes = Vec::new();
+				while let Some(byte) = try!(seq.next_element()) {
+					bytes.push(byte);
+				}
+				Ok(bytes.into())
+			}
+		}
+
+		deserializer.deserialize_any(StringVisitor)
+	}
+}
+
+/// Floats are written as JSON numbers when they are finite, and otherwise,
+/// since JSON has no infinities or NaNs, as a string of their bits in
+/// hexadecimal.
+pub mod float {
+	use std::fmt;
+	use serde::{Serializer, Deserializer};
+	use serde::de::{self, Visitor};
+
+	use super::Number;
+
+	/// Serialize a float.
+	pub fn serialize<S: Serializer>(n: &Number, serializer: S) -> Result<S::Ok, S::Error> {
+		if n.is_finite() {
+			serializer.serialize_f64(*n)
+		} else {
+			serializer.serialize_str(&format!("{:#018x}", n.to_bits()))
+		}
+	}
+
+	/// Deserialize a float.
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Number, D::Error> {
+		struct FloatVisitor;
+
+		impl<'de> Visitor<'de> for FloatVisitor {
+			type Value = Number;
+
+			fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+				f.write_str("a number or a string of hexadecimal bits")
+			}
+
+			fn visit_f64<E: de::Error>(self, n: f64) -> Result<Number, E> {
+				Ok(n)
+			}
+
+			fn visit_i64<E: de::Error>(self, n: i64) -> Result<Number, E> {
+				Ok(n as Number)
+			}
+
+			fn visit_u64<E: de::Error>(self, n: u64) -> Result<Number, E> {
+				Ok(n as Number)
+			}
+
+			fn visit_str<E: de::Error>(self, s: &str) -> Result<Number, E> {
+				s.strip_prefix("0x")
+					.and_then(|hex| u64::from_str_radix(hex, 16).ok())
+					.map(Number::from_bits)
+					.ok_or_else(|| E::invalid_value(de::Unexpected::Str(s), &self))
+			}
+		}
+
+		deserializer.deserialize_any(FloatVisitor)
+	}
+}
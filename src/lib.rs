@@ -4,13 +4,60 @@
 
 extern crate byteorder;
 extern crate libc;
+#[cfg(feature = "flate2")]
+extern crate flate2;
+#[cfg(feature = "json")]
+extern crate serde;
+#[cfg(feature = "json")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "json")]
+extern crate serde_json;
+
+use std::{ascii, fmt, str};
+use std::mem;
+use std::hash::{Hash, Hasher};
+use std::borrow::Cow;
+use std::ops::Deref;
+use std::cmp::Ordering;
 
 pub mod bytecode;
 mod write;
 mod read;
+mod validate;
+mod tree;
+mod analysis;
+mod diff;
+mod edit;
+mod disasm;
+mod asm;
+mod process;
+mod fingerprint;
+mod lineinfo;
+#[cfg(feature = "json")]
+mod json;
+#[cfg(test)]
+mod tests;
 
-pub use write::write_file;
-pub use read::read_file;
+pub use write::{write_file, write_chunk, write_chunk_with, write_chunk_to_path, write_file_to_slice,
+	write_chunk_to_slice, WriteError, WriteOptions, ChunkWriter, DEFAULT_MAX_DEPTH};
+pub use read::{read_file, read_chunk, read_chunk_counted, read_chunk_from_path, read_chunk_from_slice,
+	read_chunk_with, read_chunk_with_warnings, read_chunk_indexed, read_chunk_indexed_with, read_constants_only,
+	read_constants_only_seek, ReadError, ReadOptions, HeaderWarning, ProtoIndex, MAX_SKIPPED, DEFAULT_MAX_LEN};
+#[cfg(feature = "flate2")]
+pub use read::read_chunk_gz;
+pub use validate::{CompatError, Limit, LimitError, TerminationError, UpvalueNamesError};
+pub use tree::{ProtoPath, IterProtos, ChunkStats};
+pub use analysis::{BasicBlock, ConstantUsage, Liveness, RegEffects, UpvalueUse};
+pub use diff::{ChunkDiff, FunctionDiff};
+pub use disasm::Listing;
+pub use asm::{assemble, AssembleError};
+pub use edit::{ConstantKey, EditError, ExtractError};
+pub use process::{process_chunk, ProcessError};
+pub use fingerprint::{ChunkFingerprint, Producer};
+pub use lineinfo::{AbsLineInfo, CompactLineInfo, ABSLINEINFO};
+#[cfg(feature = "json")]
+pub use json::{to_json, from_json, ChunkDocument, JsonError, JSON_SCHEMA};
 
 /// Signature to mark Lua bytecode files.
 pub const SIGNATURE: &'static [u8] = b"\x1bLua";
@@ -25,6 +72,29 @@ pub const TEST_INT: Integer = 0x5678;
 /// A test floating-point number to know endianness.
 pub const TEST_NUMBER: Number = 370.5;
 
+/// The type tag of a `nil` constant, `LUA_TNIL`.
+pub const TAG_NIL: u8 = 0x00;
+/// The type tag of a boolean constant, `LUA_TBOOLEAN`.
+pub const TAG_BOOLEAN: u8 = 0x01;
+/// The type tag of a float constant, `LUA_TNUMFLT`. Before 5.3, this is
+/// `LUA_TNUMBER` for every number.
+pub const TAG_FLOAT: u8 = 0x03;
+/// The type tag of an integer constant, `LUA_TNUMINT`.
+pub const TAG_INT: u8 = 0x13;
+/// The type tag of a short string constant, `LUA_TSHRSTR`. Before 5.3,
+/// this is `LUA_TSTRING` for every string.
+pub const TAG_SHORT_STRING: u8 = 0x04;
+/// The type tag of a long string constant, `LUA_TLNGSTR`.
+pub const TAG_LONG_STRING: u8 = 0x14;
+
+/// The `is_vararg` bit of a Lua 5.1 function which has the compatibility
+/// `arg` table.
+pub const VARARG_HASARG: u8 = 1;
+/// The `is_vararg` bit of a Lua 5.1 function which is vararg.
+pub const VARARG_ISVARARG: u8 = 2;
+/// The `is_vararg` bit of a Lua 5.1 function which uses its `arg` table.
+pub const VARARG_NEEDSARG: u8 = 4;
+
 /// The bytecode's C `int` type.
 pub type Int = libc::c_int;
 /// The bytecodes' C `size_t` type.
@@ -36,25 +106,452 @@ pub type Integer = i64;
 /// The bytecode's `Number` (floating-point) type.
 pub type Number = f64;
 
+/// A release of Lua, each of which has its own bytecode format.
+///
+/// Lua 5.3 and 5.1 bytecode can be read and written; Lua 5.2 is known only
+/// for compatibility checks.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub enum Version {
+	/// Lua 5.1.
+	Lua51,
+	/// Lua 5.2.
+	Lua52,
+	/// Lua 5.3.
+	Lua53,
+}
+
+impl Version {
+	/// The version number as written in the header, in the form
+	/// `(MAJOR << 4) | MINOR`.
+	pub fn number(&self) -> u8 {
+		match *self {
+			Version::Lua51 => 0x51,
+			Version::Lua52 => 0x52,
+			Version::Lua53 => 0x53,
+		}
+	}
+
+	/// The version with the given header number, if it is known.
+	pub fn from_number(number: u8) -> Option<Version> {
+		match number {
+			0x51 => Some(Version::Lua51),
+			0x52 => Some(Version::Lua52),
+			0x53 => Some(Version::Lua53),
+			_ => None,
+		}
+	}
+
+	/// Whether the version has an integer subtype, and so integer constants.
+	///
+	/// Before 5.3 every number is a float, so a chunk for those versions
+	/// must not contain `Constant::Int`.
+	pub fn has_integers(&self) -> bool {
+		*self >= Version::Lua53
+	}
+}
+
+/// The byte order of values in a bytecode file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub enum Endian {
+	/// Least significant byte first.
+	Little,
+	/// Most significant byte first.
+	Big,
+}
+
+/// The platform-dependent parameters recorded in a bytecode file's header.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub struct Header {
+	/// The version of Lua whose format the file is in.
+	///
+	/// The code of a function is kept as read, and the tools here which
+	/// decode instructions, such as `Function::decoded_instructions`,
+	/// understand only Lua 5.3's opcodes.
+	pub version: Version,
+	/// The byte order of multi-byte values.
+	pub endian: Endian,
+	/// The size of the C `int` type, in bytes.
+	pub int_size: u8,
+	/// The size of the C `size_t` type, in bytes.
+	pub size_t_size: u8,
+	/// The size of the `Instruction` type, in bytes.
+	pub instruction_size: u8,
+	/// The size of the `Integer` type, in bytes.
+	///
+	/// Lua 5.1 has no integers and doesn't record this, so it is read as 8.
+	pub integer_size: u8,
+	/// The size of the `Number` type, in bytes.
+	pub number_size: u8,
+}
+
+impl Header {
+	/// The header describing the host platform, as written by its `luac`.
+	pub fn native() -> Header {
+		Header {
+			version: Version::Lua53,
+			endian: if cfg!(target_endian = "big") { Endian::Big } else { Endian::Little },
+			int_size: mem::size_of::<Int>() as u8,
+			size_t_size: mem::size_of::<Size>() as u8,
+			instruction_size: mem::size_of::<Instruction>() as u8,
+			integer_size: mem::size_of::<Integer>() as u8,
+			number_size: mem::size_of::<Number>() as u8,
+		}
+	}
+
+	/// This header with the given size of the `Number` type, such as 4 for
+	/// builds of Lua using `float`.
+	pub fn with_number_size(self, size: u8) -> Header {
+		Header { number_size: size, ..self }
+	}
+
+	/// A builder starting from this header.
+	pub fn to_builder(self) -> HeaderBuilder {
+		HeaderBuilder { header: self }
+	}
+}
+
+impl Default for Header {
+	/// The header of stock Lua 5.3 on a little-endian 64-bit platform.
+	fn default() -> Header {
+		Header {
+			version: Version::Lua53,
+			endian: Endian::Little,
+			int_size: 4,
+			size_t_size: 8,
+			instruction_size: 4,
+			integer_size: 8,
+			number_size: 8,
+		}
+	}
+}
+
+/// A builder for a `Header` which is checked to be writable.
+#[derive(Clone, Copy, Debug)]
+pub struct HeaderBuilder {
+	header: Header,
+}
+
+impl Default for HeaderBuilder {
+	fn default() -> HeaderBuilder {
+		HeaderBuilder::new()
+	}
+}
+
+impl HeaderBuilder {
+	/// A builder starting from the native header.
+	pub fn new() -> HeaderBuilder {
+		Header::native().to_builder()
+	}
+
+	/// Set the version of Lua whose format to write.
+	pub fn version(mut self, version: Version) -> HeaderBuilder {
+		self.header.version = version;
+		self
+	}
+
+	/// Set the byte order.
+	pub fn endian(mut self, endian: Endian) -> HeaderBuilder {
+		self.header.endian = endian;
+		self
+	}
+
+	/// Set the size of the C `int` type.
+	pub fn int_size(mut self, size: u8) -> HeaderBuilder {
+		self.header.int_size = size;
+		self
+	}
+
+	/// Set the size of the C `size_t` type.
+	pub fn size_t_size(mut self, size: u8) -> HeaderBuilder {
+		self.header.size_t_size = size;
+		self
+	}
+
+	/// Set the size of the `Instruction` type.
+	pub fn instruction_size(mut self, size: u8) -> HeaderBuilder {
+		self.header.instruction_size = size;
+		self
+	}
+
+	/// Set the size of the `Integer` type.
+	pub fn integer_size(mut self, size: u8) -> HeaderBuilder {
+		self.header.integer_size = size;
+		self
+	}
+
+	/// Set the size of the `Number` type.
+	pub fn number_size(mut self, size: u8) -> HeaderBuilder {
+		self.header.number_size = size;
+		self
+	}
+
+	/// Finish the header, failing if chunks could not be written with it.
+	pub fn build(self) -> Result<Header, WriteError> {
+		try!(self.header.check_writable());
+		Ok(self.header)
+	}
+}
+
+/// A complete bytecode file.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub struct Chunk {
+	/// The parameters the file was written with.
+	pub header: Header,
+	/// The main function of the file.
+	pub main: Function,
+}
+
+/// A string as stored in bytecode.
+///
+/// Lua strings are arbitrary bytes which need not be UTF-8 and may contain
+/// NULs, so they are kept as bytes, and written back exactly as read.
+///
+/// Strings are compared, ordered, and hashed by their bytes alone, not by
+/// how their length was encoded.
+#[derive(Clone, Default)]
+pub struct LuaString {
+	bytes: Vec<u8>,
+	long_length: bool,
+}
+
+impl LuaString {
+	/// A string of the given bytes.
+	pub fn new<B: Into<Vec<u8>>>(bytes: B) -> LuaString {
+		LuaString { bytes: bytes.into(), long_length: false }
+	}
+
+	/// Whether the string's length was read in the long form, a byte of
+	/// 0xff followed by a `size_t`, although it would fit in a single byte.
+	///
+	/// This is only recorded when reading with
+	/// `ReadOptions::preserve_encoding`, and only reproduced when writing
+	/// with `WriteOptions::preserve_encoding`.
+	pub fn long_length(&self) -> bool {
+		self.long_length
+	}
+
+	/// Set whether to write the string's length in the long form even if it
+	/// would fit in a single byte.
+	pub fn set_long_length(&mut self, long_length: bool) {
+		self.long_length = long_length;
+	}
+
+	/// The bytes of the string.
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.bytes
+	}
+
+	/// Take the bytes of the string.
+	pub fn into_bytes(self) -> Vec<u8> {
+		self.bytes
+	}
+
+	/// The string as UTF-8, if it is valid UTF-8.
+	pub fn as_str(&self) -> Option<&str> {
+		str::from_utf8(&self.bytes).ok()
+	}
+
+	/// The string as UTF-8, with invalid sequences replaced by U+FFFD.
+	pub fn to_string_lossy(&self) -> Cow<'_, str> {
+		String::from_utf8_lossy(&self.bytes)
+	}
+}
+
+impl Deref for LuaString {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		&self.bytes
+	}
+}
+
+impl From<Vec<u8>> for LuaString {
+	fn from(bytes: Vec<u8>) -> LuaString {
+		LuaString::new(bytes)
+	}
+}
+
+impl<'a> From<&'a [u8]> for LuaString {
+	fn from(bytes: &'a [u8]) -> LuaString {
+		LuaString::new(bytes)
+	}
+}
+
+impl From<String> for LuaString {
+	fn from(string: String) -> LuaString {
+		LuaString::new(string)
+	}
+}
+
+impl<'a> From<&'a str> for LuaString {
+	fn from(string: &'a str) -> LuaString {
+		LuaString::new(string)
+	}
+}
+
+impl PartialEq for LuaString {
+	fn eq(&self, other: &LuaString) -> bool {
+		self.bytes == other.bytes
+	}
+}
+
+impl Eq for LuaString {}
+
+impl PartialOrd for LuaString {
+	fn partial_cmp(&self, other: &LuaString) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for LuaString {
+	fn cmp(&self, other: &LuaString) -> Ordering {
+		self.bytes.cmp(&other.bytes)
+	}
+}
+
+impl Hash for LuaString {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		self.bytes.hash(state)
+	}
+}
+
+impl PartialEq<str> for LuaString {
+	fn eq(&self, other: &str) -> bool {
+		self.bytes == other.as_bytes()
+	}
+}
+
+impl<'a> PartialEq<&'a str> for LuaString {
+	fn eq(&self, other: &&'a str) -> bool {
+		self.bytes == other.as_bytes()
+	}
+}
+
+/// Formats the string as a Rust byte string literal.
+impl fmt::Debug for LuaString {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		try!(f.write_str("b\""));
+		for &byte in &self.bytes {
+			for escaped in ascii::escape_default(byte) {
+				try!(fmt::Write::write_char(f, escaped as char));
+			}
+		}
+		f.write_str("\"")
+	}
+}
+
+/// Formats the string as UTF-8, replacing invalid sequences.
+impl fmt::Display for LuaString {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.to_string_lossy())
+	}
+}
+
 /// An entry in the constant pool.
-#[derive(Clone, Debug, PartialEq)]
+///
+/// Floats are compared and hashed by their bits, so `0.0` and `-0.0`
+/// differ while a NaN equals itself.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
 pub enum Constant {
 	/// The value `nil`.
 	Nil,
 	/// A boolean.
 	Boolean(bool),
-	/// A floating-point number.
-	Float(Number),
+	/// A floating-point number. Numbers stored as 4-byte floats are widened
+	/// when read and narrowed when written.
+	Float(#[cfg_attr(feature = "json", serde(with = "json::float"))] Number),
+	/// A floating-point number of a size other than 4 or 8 bytes, as its
+	/// raw bytes in the file's byte order.
+	RawFloat(Vec<u8>),
 	/// An integer.
 	Int(Integer),
-	/// A short string.
-	ShortString(String),
-	/// A long string. Behaves the same as `ShortString`.
-	LongString(String),
+	/// A short string. Strings are written with their length rather than
+	/// terminated, so they may contain NUL bytes.
+	ShortString(LuaString),
+	/// A long string. Behaves the same as `ShortString`, but is kept
+	/// distinct so that writing reproduces the original tag.
+	LongString(LuaString),
+}
+
+/// Formats a constant as `luac -l` does, with strings quoted and escaped.
+impl fmt::Display for Constant {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Constant::Nil => write!(f, "nil"),
+			Constant::Boolean(b) => write!(f, "{}", b),
+			Constant::Float(n) => write!(f, "{}", disasm::format_number(n)),
+			Constant::RawFloat(ref bytes) => {
+				try!(write!(f, "float(0x"));
+				for byte in bytes {
+					try!(write!(f, "{:02x}", byte));
+				}
+				write!(f, ")")
+			}
+			Constant::Int(i) => write!(f, "{}", i),
+			Constant::ShortString(ref s) | Constant::LongString(ref s) => disasm::write_quoted(f, s),
+		}
+	}
+}
+
+impl Constant {
+	/// The type tag written before the constant in the given version's
+	/// format.
+	///
+	/// Versions before 5.3 have a single tag for numbers and one for
+	/// strings. Returns `None` for `Int` in those versions, which have no
+	/// integers.
+	pub fn type_tag(&self, version: Version) -> Option<u8> {
+		Some(match *self {
+			Constant::Nil => TAG_NIL,
+			Constant::Boolean(_) => TAG_BOOLEAN,
+			Constant::Float(_) | Constant::RawFloat(_) => TAG_FLOAT,
+			Constant::Int(_) if version.has_integers() => TAG_INT,
+			Constant::Int(_) => return None,
+			Constant::LongString(_) if version >= Version::Lua53 => TAG_LONG_STRING,
+			Constant::ShortString(_) | Constant::LongString(_) => TAG_SHORT_STRING,
+		})
+	}
+}
+
+impl PartialEq for Constant {
+	fn eq(&self, other: &Constant) -> bool {
+		match (self, other) {
+			(&Constant::Nil, &Constant::Nil) => true,
+			(&Constant::Boolean(a), &Constant::Boolean(b)) => a == b,
+			(&Constant::Float(a), &Constant::Float(b)) => a.to_bits() == b.to_bits(),
+			(&Constant::RawFloat(ref a), &Constant::RawFloat(ref b)) => a == b,
+			(&Constant::Int(a), &Constant::Int(b)) => a == b,
+			(&Constant::ShortString(ref a), &Constant::ShortString(ref b)) => a == b,
+			(&Constant::LongString(ref a), &Constant::LongString(ref b)) => a == b,
+			_ => false,
+		}
+	}
+}
+
+impl Eq for Constant {}
+
+impl Hash for Constant {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		mem::discriminant(self).hash(state);
+		match *self {
+			Constant::Nil => {}
+			Constant::Boolean(b) => b.hash(state),
+			Constant::Float(n) => n.to_bits().hash(state),
+			Constant::RawFloat(ref bytes) => bytes.hash(state),
+			Constant::Int(i) => i.hash(state),
+			Constant::ShortString(ref s) | Constant::LongString(ref s) => s.hash(state),
+		}
+	}
 }
 
 /// An entry in the upvalue table.
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
 pub enum Upvalue {
 	/// An upvalue inherited from the outer function's upvalues.
 	Outer(u8),
@@ -63,10 +560,11 @@ pub enum Upvalue {
 }
 
 /// An entry in the local variable debug table.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
 pub struct LocalVar {
 	/// The local variable's name.
-	pub name: String,
+	pub name: LuaString,
 	/// The instruction at which the local variable is introduced.
 	pub start_pc: Int,
 	/// The instruction at which the local variable goes out of scope.
@@ -74,14 +572,19 @@ pub struct LocalVar {
 }
 
 /// Optional debugging information for a function.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
 pub struct Debug {
 	/// The line number of each bytecode instruction.
+	///
+	/// Lua 5.3 stores each line in full. Lua 5.4 instead stores a signed
+	/// byte delta per instruction along with absolute anchors, which is not
+	/// supported.
 	pub lineinfo: Vec<Int>,
 	/// The names and scopes of local variables.
 	pub localvars: Vec<LocalVar>,
 	/// The names of upvalues.
-	pub upvalues: Vec<String>,
+	pub upvalues: Vec<LuaString>,
 }
 
 impl Debug {
@@ -95,19 +598,43 @@ impl Debug {
 	}
 }
 
+/// The origin of a function, decoded from its `source`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SourceName<'a> {
+	/// A file name, encoded with a leading `@`.
+	File(Cow<'a, str>),
+	/// A descriptive name, encoded with a leading `=`.
+	Name(Cow<'a, str>),
+	/// The source text itself, encoded as-is.
+	Literal(Cow<'a, str>),
+}
+
 /// A Lua function prototype.
-#[derive(Clone, Debug, PartialEq)]
+///
+/// The default is an empty function with no source, parameters, code, or
+/// debug information. It is not valid to write until it has code.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
 pub struct Function {
-	/// The source filename of the function. May be empty.
-	pub source: String,
+	/// The source filename of the function.
+	///
+	/// `None` is distinct from an empty string: it is how a stripped chunk,
+	/// or a nested function sharing its parent's source, records no source.
+	pub source: Option<LuaString>,
 	/// The start line number of the function.
 	pub line_start: Int,
 	/// The end line number of the function.
 	pub line_end: Int,
 	/// The number of fixed parameters the function takes.
 	pub num_params: u8,
-	/// Whether the function accepts a variable number of arguments.
-	pub is_vararg: bool,
+	/// Whether the function accepts a variable number of arguments, as
+	/// the raw byte written in the bytecode.
+	///
+	/// Lua 5.3 writes this as 0 or 1. Lua 5.1 instead writes a bitmask of
+	/// `VARARG_HASARG`, `VARARG_ISVARARG`, and `VARARG_NEEDSARG` for its
+	/// compatibility `arg` table. Use `has_vararg`, `has_arg`, and
+	/// `needs_arg` rather than testing the bits directly.
+	pub is_vararg: u8,
 	/// The number of registers needed by the function.
 	pub max_stack_size: u8,
 	/// The function's code.
@@ -121,3 +648,106 @@ pub struct Function {
 	/// Debugging information for the function.
 	pub debug: Debug,
 }
+
+impl Chunk {
+	/// A chunk with the given main function and the native header.
+	pub fn new(main: Function) -> Chunk {
+		Chunk {
+			header: Header::native(),
+			main,
+		}
+	}
+}
+
+impl Function {
+	/// The smallest valid main function, which immediately returns.
+	///
+	/// Like any main function, it is vararg and has the `_ENV` upvalue.
+	pub fn empty_main() -> Function {
+		Function {
+			source: None,
+			line_start: 0,
+			line_end: 0,
+			num_params: 0,
+			is_vararg: 1,
+			max_stack_size: 2,
+			code: vec![bytecode::encode(bytecode::Opcode::Return, 0, 1, 0)],
+			constants: vec![],
+			upvalues: vec![Upvalue::Stack(0)],
+			protos: vec![],
+			debug: Debug::none(),
+		}
+	}
+
+	/// Whether the function accepts a variable number of arguments.
+	///
+	/// Any nonzero `is_vararg` means so in every version, since Lua 5.1
+	/// never sets its other bits without `VARARG_ISVARARG`.
+	pub fn has_vararg(&self) -> bool {
+		self.is_vararg != 0
+	}
+
+	/// Whether the function has Lua 5.1's compatibility `arg` table when
+	/// loaded by the given version. Always `false` after 5.1.
+	pub fn has_arg(&self, version: Version) -> bool {
+		version == Version::Lua51 && self.is_vararg & VARARG_HASARG != 0
+	}
+
+	/// Whether the function uses Lua 5.1's compatibility `arg` table when
+	/// loaded by the given version. Always `false` after 5.1.
+	pub fn needs_arg(&self, version: Version) -> bool {
+		version == Version::Lua51 && self.is_vararg & VARARG_NEEDSARG != 0
+	}
+
+	/// Iterate over the function's code, decoding each instruction.
+	///
+	/// Each item is paired with its program counter, which indexes into
+	/// `code` and `debug.lineinfo`.
+	pub fn decoded_instructions(&self) -> bytecode::DecodedInstructions<'_> {
+		bytecode::DecodedInstructions::new(&self.code)
+	}
+
+	/// The source line of the instruction at `pc`.
+	///
+	/// Returns `None` if the function is stripped or `pc` is out of range.
+	/// For line info in Lua 5.4's compact form, use
+	/// `CompactLineInfo::line_at`.
+	pub fn line_at(&self, pc: usize) -> Option<Int> {
+		self.debug.lineinfo.get(pc).cloned()
+	}
+
+	/// Whether the function has no debug information, as when compiled
+	/// with `luac -s`.
+	///
+	/// This is true when the line info, local variables, and upvalue names
+	/// are all empty, and says nothing about nested functions. Without
+	/// debug information, errors can't be mapped back to source lines.
+	pub fn is_stripped(&self) -> bool {
+		self.debug.lineinfo.is_empty() && self.debug.localvars.is_empty() && self.debug.upvalues.is_empty()
+	}
+
+	/// Decode the `@file` and `=name` conventions of the function's source.
+	///
+	/// Returns `None` if the function has no source. Bytes which aren't
+	/// valid UTF-8 are replaced by U+FFFD.
+	pub fn source_name(&self) -> Option<SourceName<'_>> {
+		self.source.as_ref().map(|source| {
+			if let Some(file) = source.strip_prefix(b"@") {
+				SourceName::File(String::from_utf8_lossy(file))
+			} else if let Some(name) = source.strip_prefix(b"=") {
+				SourceName::Name(String::from_utf8_lossy(name))
+			} else {
+				SourceName::Literal(source.to_string_lossy())
+			}
+		})
+	}
+
+	/// Set the function's source, encoding it with the matching convention.
+	pub fn set_source_name(&mut self, name: SourceName) {
+		self.source = Some(match name {
+			SourceName::File(file) => format!("@{}", file),
+			SourceName::Name(name) => format!("={}", name),
+			SourceName::Literal(text) => text.into_owned(),
+		}.into());
+	}
+}
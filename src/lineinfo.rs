@@ -0,0 +1,106 @@
+//! Line info in Lua 5.4's compact form.
+
+use super::{Function, Int};
+
+/// The largest absolute difference between consecutive lines which is
+/// stored as a delta, plus one, `LIMLINEDIFF`.
+const LIMLINEDIFF: Int = 0x80;
+
+/// The most instructions between absolute line entries, `MAXIWTHABS`.
+const MAXIWTHABS: usize = 128;
+
+/// The delta marking an instruction whose line is in `abslineinfo`.
+pub const ABSLINEINFO: i8 = -0x80;
+
+/// The line of an instruction, stored in full rather than as a delta.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct AbsLineInfo {
+	/// The program counter of the instruction.
+	pub pc: Int,
+	/// The instruction's source line.
+	pub line: Int,
+}
+
+/// A function's line info as Lua 5.4 stores it: a signed byte per
+/// instruction giving the difference from the previous instruction's line,
+/// with an absolute line recorded wherever the difference doesn't fit in a
+/// byte, and at least every `MAXIWTHABS` instructions so that lookups need
+/// not sum every delta from the start.
+///
+/// Deltas start from the function's `line_start`. An instruction with an
+/// absolute line has the delta `ABSLINEINFO`.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct CompactLineInfo {
+	/// The difference of each instruction's line from the previous one's.
+	pub lineinfo: Vec<i8>,
+	/// The absolute lines, in order of program counter.
+	pub abslineinfo: Vec<AbsLineInfo>,
+}
+
+impl CompactLineInfo {
+	/// Encode a line per instruction, as Lua 5.4's `savelineinfo` does.
+	pub fn encode(lines: &[Int], line_start: Int) -> CompactLineInfo {
+		let mut compact = CompactLineInfo::default();
+		let mut previous = line_start;
+		// Instructions since the last absolute line.
+		let mut with_abs = 0;
+		for (pc, &line) in lines.iter().enumerate() {
+			let delta = line.wrapping_sub(previous);
+			if delta <= -LIMLINEDIFF || delta >= LIMLINEDIFF || with_abs >= MAXIWTHABS {
+				compact.abslineinfo.push(AbsLineInfo { pc: pc as Int, line });
+				compact.lineinfo.push(ABSLINEINFO);
+				with_abs = 1;
+			} else {
+				compact.lineinfo.push(delta as i8);
+				with_abs += 1;
+			}
+			previous = line;
+		}
+		compact
+	}
+
+	/// Decode the line of every instruction.
+	pub fn decode(&self, line_start: Int) -> Vec<Int> {
+		let mut lines = Vec::with_capacity(self.lineinfo.len());
+		let mut abs = self.abslineinfo.iter().peekable();
+		let mut line = line_start;
+		for (pc, &delta) in self.lineinfo.iter().enumerate() {
+			line = match abs.peek() {
+				Some(entry) if entry.pc as usize == pc => entry.line,
+				_ => line.wrapping_add(delta as Int),
+			};
+			if abs.peek().map(|entry| entry.pc as usize) == Some(pc) {
+				abs.next();
+			}
+			lines.push(line);
+		}
+		lines
+	}
+
+	/// The line of the instruction at `pc`, found as Lua 5.4's
+	/// `luaG_getfuncline` does, from the last absolute line at or before it.
+	///
+	/// Returns `None` if `pc` is out of range.
+	pub fn line_at(&self, pc: usize, line_start: Int) -> Option<Int> {
+		if pc >= self.lineinfo.len() {
+			return None;
+		}
+		let (base_pc, mut line) = match self.abslineinfo.iter().take_while(|entry| entry.pc as usize <= pc).last() {
+			Some(entry) => (entry.pc as usize + 1, entry.line),
+			None => (0, line_start),
+		};
+		for &delta in &self.lineinfo[base_pc..pc + 1] {
+			line = line.wrapping_add(delta as Int);
+		}
+		Some(line)
+	}
+}
+
+impl Function {
+	/// The function's line info in Lua 5.4's compact form.
+	///
+	/// Only the line info is converted; nested functions are not included.
+	pub fn compact_lineinfo(&self) -> CompactLineInfo {
+		CompactLineInfo::encode(&self.debug.lineinfo, self.line_start)
+	}
+}
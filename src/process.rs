@@ -0,0 +1,65 @@
+//! Reading, transforming, and rewriting chunks in one step.
+
+use std::{error, fmt};
+
+use read::{read_chunk, ReadError};
+use write::{write_chunk, WriteError};
+use super::Chunk;
+
+/// An error encountered while processing a chunk.
+#[derive(Debug)]
+pub enum ProcessError {
+	/// The input could not be read.
+	Read(ReadError),
+	/// The transformed chunk could not be written.
+	Write(WriteError),
+}
+
+impl fmt::Display for ProcessError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			ProcessError::Read(ref err) => write!(f, "reading: {}", err),
+			ProcessError::Write(ref err) => write!(f, "writing: {}", err),
+		}
+	}
+}
+
+impl error::Error for ProcessError {
+	fn description(&self) -> &str {
+		match *self {
+			ProcessError::Read(..) => "error reading chunk",
+			ProcessError::Write(..) => "error writing chunk",
+		}
+	}
+
+	fn cause(&self) -> Option<&error::Error> {
+		match *self {
+			ProcessError::Read(ref err) => Some(err),
+			ProcessError::Write(ref err) => Some(err),
+		}
+	}
+}
+
+impl From<ReadError> for ProcessError {
+	fn from(err: ReadError) -> ProcessError {
+		ProcessError::Read(err)
+	}
+}
+
+impl From<WriteError> for ProcessError {
+	fn from(err: WriteError) -> ProcessError {
+		ProcessError::Write(err)
+	}
+}
+
+/// Read a chunk, pass it to `f` to be modified, and write it back.
+///
+/// The chunk is written with its header as `f` leaves it, which is the
+/// header it was read with unless `f` changes it.
+pub fn process_chunk<F: FnOnce(&mut Chunk)>(input: &[u8], f: F) -> Result<Vec<u8>, ProcessError> {
+	let mut chunk = try!(read_chunk(input));
+	f(&mut chunk);
+	let mut output = Vec::with_capacity(input.len());
+	try!(write_chunk(&mut output, &chunk));
+	Ok(output)
+}
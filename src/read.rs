@@ -1,30 +1,449 @@
 //! Deserialization code.
 
-use std::io::{self, Read};
-use std::mem::size_of;
-use byteorder::ReadBytesExt;
-use byteorder::NativeEndian as E;
+use std::{error, fmt};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::marker::PhantomData;
+use byteorder::{self, ReadBytesExt, ByteOrder, LittleEndian, BigEndian};
 
+use bytecode::{SIZE_OP, SIZE_A, SIZE_C, MAXARG_A};
+use write::DEFAULT_MAX_DEPTH;
 use super::{
 	SIGNATURE, FORMAT, VERSION, DATA, TEST_INT, TEST_NUMBER,
-	Int, Size, Instruction, Integer, Number,
-	Constant, Upvalue, LocalVar, Debug, Function,
+	TAG_NIL, TAG_BOOLEAN, TAG_FLOAT, TAG_INT, TAG_SHORT_STRING, TAG_LONG_STRING,
+	Int, Instruction, Integer, Number,
+	Constant, Upvalue, LocalVar, Debug, Function, Endian, Header, Chunk, Version, ProtoPath, LuaString,
 };
 
+/// Signature of LuaJIT bytecode files.
+const LUAJIT_SIGNATURE: &'static [u8] = b"\x1bLJ";
+
+/// Lua 5.1's `MOVE` opcode, which describes an upvalue in a register.
+const OP51_MOVE: u32 = 0;
+/// Lua 5.1's `GETUPVAL` opcode, which describes an upvalue of the parent.
+const OP51_GETUPVAL: u32 = 4;
+/// Lua 5.1's `CLOSURE` opcode.
+const OP51_CLOSURE: u32 = 36;
+
+/// An error encountered while deserializing bytecode.
+#[derive(Debug)]
+pub enum ReadError {
+	/// The underlying reader failed.
+	Io(io::Error),
+	/// The input ended before the first byte of a chunk, as at the end of
+	/// a stream of concatenated chunks.
+	Eof,
+	/// The input ended partway through a chunk.
+	Truncated,
+	/// A length exceeded `ReadOptions::max_len`, which usually means the
+	/// header's sizes don't match the data.
+	ImplausibleLength {
+		/// What the length is of.
+		field: &'static str,
+		/// The length read.
+		len: u64,
+	},
+	/// The bytecode was malformed or unsupported.
+	Invalid(String),
+	/// Functions were nested more deeply than `ReadOptions::max_depth`.
+	TooDeep(usize),
+	/// The file is LuaJIT bytecode, which is a different format.
+	LuaJit {
+		/// The LuaJIT bytecode version.
+		bc_version: u8,
+		/// The LuaJIT header flags.
+		flags: u8,
+	},
+}
+
+impl fmt::Display for ReadError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			ReadError::Io(ref err) => err.fmt(f),
+			ReadError::Eof => f.write_str("no chunk before the end of input"),
+			ReadError::Truncated => f.write_str("unexpected end of input"),
+			ReadError::ImplausibleLength { field, len } => write!(f,
+				"implausible {} length {}; the header's sizes may not match the data", field, len),
+			ReadError::Invalid(ref msg) => f.write_str(msg),
+			ReadError::TooDeep(max) => write!(f, "functions nested more than {} deep", max),
+			ReadError::LuaJit { bc_version, flags } => write!(f,
+				"LuaJIT bytecode version {} with flags {:#x} is not supported", bc_version, flags),
+		}
+	}
+}
+
+impl error::Error for ReadError {
+	fn description(&self) -> &str {
+		match *self {
+			ReadError::Io(..) => "I/O error",
+			ReadError::Eof => "no chunk before the end of input",
+			ReadError::Truncated => "unexpected end of input",
+			ReadError::ImplausibleLength { .. } => "implausible length",
+			ReadError::Invalid(ref msg) => msg,
+			ReadError::TooDeep(..) => "functions nested too deeply",
+			ReadError::LuaJit { .. } => "LuaJIT bytecode is not supported",
+		}
+	}
+
+	fn cause(&self) -> Option<&error::Error> {
+		match *self {
+			ReadError::Io(ref err) => Some(err),
+			_ => None,
+		}
+	}
+}
+
+impl From<io::Error> for ReadError {
+	fn from(err: io::Error) -> ReadError {
+		match err.kind() {
+			io::ErrorKind::UnexpectedEof => ReadError::Truncated,
+			_ => ReadError::Io(err),
+		}
+	}
+}
+
+impl From<ReadError> for io::Error {
+	fn from(err: ReadError) -> io::Error {
+		match err {
+			ReadError::Io(err) => err,
+			ReadError::Eof | ReadError::Truncated => io::Error::new(io::ErrorKind::UnexpectedEof, err),
+			_ => io::Error::new(io::ErrorKind::InvalidData, err),
+		}
+	}
+}
+
+impl From<byteorder::Error> for ReadError {
+	fn from(err: byteorder::Error) -> ReadError {
+		match err {
+			byteorder::Error::UnexpectedEOF => ReadError::Truncated,
+			byteorder::Error::Io(err) => err.into(),
+		}
+	}
+}
+
+/// A header check which failed but was tolerated because
+/// `ReadOptions::lenient_header` was set.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeaderWarning {
+	/// The format byte was not 0, the official format.
+	Format(u8),
+	/// The bytes `LUAC_DATA`, which catch newline conversion, were wrong.
+	Data([u8; 6]),
+	/// The test number was not `LUAC_NUM`, so `Number` may be laid out
+	/// differently than expected.
+	TestNumber(Number),
+}
+
+impl fmt::Display for HeaderWarning {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			HeaderWarning::Format(format) => write!(f,
+				"invalid format, expected {:?} but got {:?}", FORMAT, format),
+			HeaderWarning::Data(ref data) => write!(f,
+				"invalid test data, expected {:?} but got {:?}", DATA, &data[..]),
+			HeaderWarning::TestNumber(number) => write!(f,
+				"invalid test number, expected {:?} but got {:?}", TEST_NUMBER, number),
+		}
+	}
+}
+
+/// Options controlling how strictly bytecode is checked while reading.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ReadOptions {
+	/// Reject functions which have upvalue names but not exactly one per
+	/// upvalue. Lua writes either every name or, when stripping, none, so
+	/// any other count indicates a corrupt or hand-edited chunk.
+	///
+	/// Defaults to `true`.
+	pub check_upvalue_names: bool,
+	/// Skip any bytes before the signature, such as a byte order mark or a
+	/// shebang line, searching at most `MAX_SKIPPED` bytes for it.
+	///
+	/// Defaults to `false`.
+	pub skip_to_signature: bool,
+	/// The longest string, in bytes, and the most elements of any list
+	/// which are accepted before failing with `ImplausibleLength`, rather
+	/// than trying to allocate or read that much.
+	///
+	/// Defaults to `DEFAULT_MAX_LEN`.
+	pub max_len: u64,
+	/// The deepest nesting of functions which is read before failing with
+	/// `TooDeep`, rather than overflowing the stack on a chunk crafted to
+	/// nest deeply. The main function is at depth 0.
+	///
+	/// Defaults to `DEFAULT_MAX_DEPTH`, as for `WriteOptions::max_depth`.
+	pub max_depth: usize,
+	/// Tolerate a header with an unexpected format byte, test data, or test
+	/// number, as from a patched build of Lua 5.3, rather than failing.
+	/// The sizes and test integer must still be valid, since the rest of
+	/// the chunk can't be read without them. Use `read_chunk_with_warnings`
+	/// to learn which checks failed.
+	///
+	/// Defaults to `false`.
+	pub lenient_header: bool,
+	/// Record which strings had their length written in the long form
+	/// although it would fit in a single byte, as some tools other than Lua
+	/// do, so that `WriteOptions::preserve_encoding` can reproduce it.
+	///
+	/// Defaults to `false`.
+	pub preserve_encoding: bool,
+}
+
+/// The most bytes `ReadOptions::skip_to_signature` skips before giving up.
+pub const MAX_SKIPPED: usize = 1024;
+
+/// The default `ReadOptions::max_len`, 256 MiB.
+pub const DEFAULT_MAX_LEN: u64 = 1 << 28;
+
+impl Default for ReadOptions {
+	fn default() -> ReadOptions {
+		ReadOptions {
+			check_upvalue_names: true,
+			skip_to_signature: false,
+			max_len: DEFAULT_MAX_LEN,
+			max_depth: DEFAULT_MAX_DEPTH,
+			lenient_header: false,
+			preserve_encoding: false,
+		}
+	}
+}
+
 /// Deserialize bytecode into a `Function`.
+///
+/// Errors are converted to `io::Error`; use `read_chunk` to get a
+/// `ReadError` instead.
 pub fn read_file<R: Read>(read: R) -> io::Result<Function> {
-	let mut reader = Reader { out: read };
-	try!(reader.read_header());
-	try!(reader.out.read_u8()); // discard upvals header
-	reader.read_function()
+	Ok(try!(read_chunk(read)).main)
+}
+
+/// Deserialize bytecode into a `Chunk`, detecting its byte order.
+///
+/// Reading stops after the main function. Any bytes following it are left
+/// unread; use `read_chunk_counted` to locate them.
+pub fn read_chunk<R: Read>(read: R) -> Result<Chunk, ReadError> {
+	read_chunk_with(read, &ReadOptions::default())
+}
+
+/// Deserialize bytecode into a `Chunk` with the given options.
+pub fn read_chunk_with<R: Read>(read: R, options: &ReadOptions) -> Result<Chunk, ReadError> {
+	read_chunk_with_warnings(read, options).map(|(chunk, _)| chunk)
+}
+
+/// Deserialize bytecode into a `Chunk` with the given options, also
+/// returning the header checks which failed but were tolerated.
+///
+/// The warnings are always empty unless `options.lenient_header` is set.
+pub fn read_chunk_with_warnings<R: Read>(mut read: R, options: &ReadOptions)
+	-> Result<(Chunk, Vec<HeaderWarning>), ReadError>
+{
+	let mut warnings = Vec::new();
+	let header = try!(read_header_with(&mut read, options, &mut warnings));
+	let main = try!(match header.endian {
+		Endian::Little => Reader::<_, LittleEndian>::new(Streamed(read), header, *options).read_main(),
+		Endian::Big => Reader::<_, BigEndian>::new(Streamed(read), header, *options).read_main(),
+	});
+	Ok((Chunk {
+		header,
+		main,
+	}, warnings))
+}
+
+/// Read only the constants of a chunk's main function.
+///
+/// The main function's code is skipped over and nothing after its
+/// constants is read, which is much faster than reading the whole chunk
+/// when only, for example, its strings are of interest.
+pub fn read_constants_only<R: Read>(read: R) -> Result<Vec<Constant>, ReadError> {
+	read_constants_from(Streamed(read))
+}
+
+/// Read only the constants of a chunk's main function, as
+/// `read_constants_only`, seeking past the code rather than reading it.
+pub fn read_constants_only_seek<R: Read + Seek>(read: R) -> Result<Vec<Constant>, ReadError> {
+	read_constants_from(Seekable(read))
+}
+
+fn read_constants_from<S: Source>(mut read: S) -> Result<Vec<Constant>, ReadError> {
+	let header = try!(read_header(&mut read));
+	let options = ReadOptions::default();
+	match header.endian {
+		Endian::Little => Reader::<_, LittleEndian>::new(read, header, options).read_constants_only(),
+		Endian::Big => Reader::<_, BigEndian>::new(read, header, options).read_constants_only(),
+	}
+}
+
+/// Decompress gzipped bytecode and deserialize it into a `Chunk`.
+///
+/// Requires the `flate2` feature.
+#[cfg(feature = "flate2")]
+pub fn read_chunk_gz<R: Read>(read: R) -> Result<Chunk, ReadError> {
+	read_chunk(::flate2::read::GzDecoder::new(read))
 }
 
-struct Reader<R: Read> {
+/// Deserialize bytecode from a byte slice into a `Chunk`.
+///
+/// This performs no I/O, so it never fails with `ReadError::Io`. An empty
+/// slice fails with `ReadError::Eof` and one ending partway through the
+/// chunk with `ReadError::Truncated`; other errors are due to invalid
+/// bytecode.
+pub fn read_chunk_from_slice(data: &[u8]) -> Result<Chunk, ReadError> {
+	read_chunk(data)
+}
+
+impl Chunk {
+	/// Deserialize bytecode from a byte slice.
+	///
+	/// Equivalent to `read_chunk_from_slice`.
+	pub fn from_bytes(data: &[u8]) -> Result<Chunk, ReadError> {
+		read_chunk_from_slice(data)
+	}
+}
+
+/// Deserialize bytecode from the file at `path` into a `Chunk`.
+pub fn read_chunk_from_path<P: AsRef<Path>>(path: P) -> Result<Chunk, ReadError> {
+	read_chunk(BufReader::new(try!(File::open(path))))
+}
+
+/// Deserialize bytecode into a `Chunk`, also returning the number of bytes
+/// it occupied.
+pub fn read_chunk_counted<R: Read>(read: R) -> Result<(Chunk, usize), ReadError> {
+	let mut counted = Counted { inner: read, count: 0 };
+	let chunk = try!(read_chunk(&mut counted));
+	Ok((chunk, counted.count))
+}
+
+/// The byte range of each function in a chunk, as returned by
+/// `read_chunk_indexed`.
+///
+/// Offsets are from the start of the input, and each range runs from a
+/// function's source to the end of its debug info, including its nested
+/// functions.
+pub type ProtoIndex = BTreeMap<ProtoPath, (usize, usize)>;
+
+/// Deserialize bytecode into a `Chunk`, also returning the byte range each
+/// function occupied.
+///
+/// The ranges allow a tool to later re-read or patch a single function
+/// without reading the whole chunk again.
+pub fn read_chunk_indexed<R: Read>(read: R) -> Result<(Chunk, ProtoIndex), ReadError> {
+	read_chunk_indexed_with(read, &ReadOptions::default())
+}
+
+/// Deserialize bytecode into a `Chunk` with the given options, also
+/// returning the byte range each function occupied.
+///
+/// Offsets are from the start of the input, including any bytes skipped
+/// by `ReadOptions::skip_to_signature`.
+pub fn read_chunk_indexed_with<R: Read>(read: R, options: &ReadOptions)
+	-> Result<(Chunk, ProtoIndex), ReadError>
+{
+	let mut counted = Counted { inner: read, count: 0 };
+	let header = try!(read_header_with(&mut counted, options, &mut Vec::new()));
+	let (main, index) = try!(match header.endian {
+		Endian::Little => Reader::<_, LittleEndian>::new(counted, header, *options).read_indexed(),
+		Endian::Big => Reader::<_, BigEndian>::new(counted, header, *options).read_indexed(),
+	});
+	Ok((Chunk {
+		header,
+		main,
+	}, index))
+}
+
+struct Counted<R: Read> {
+	inner: R,
+	count: usize,
+}
+
+impl<R: Read> Read for Counted<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let n = try!(self.inner.read(buf));
+		self.count += n;
+		Ok(n)
+	}
+}
+
+impl<R: Read> Source for Counted<R> {
+	fn skip(&mut self, len: u64) -> Result<(), ReadError> {
+		let skipped = try!(io::copy(&mut (&mut *self).take(len), &mut io::sink()));
+		if skipped < len {
+			return Err(ReadError::Truncated);
+		}
+		Ok(())
+	}
+
+	fn position(&self) -> Option<usize> {
+		Some(self.count)
+	}
+}
+
+/// A reader which can skip over bytes it doesn't need.
+trait Source: Read {
+	/// Skip `len` bytes, failing if fewer remain.
+	fn skip(&mut self, len: u64) -> Result<(), ReadError>;
+
+	/// The number of bytes read so far, if it is tracked.
+	fn position(&self) -> Option<usize> {
+		None
+	}
+}
+
+/// A source which skips by reading and discarding.
+struct Streamed<R: Read>(R);
+
+impl<R: Read> Read for Streamed<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.0.read(buf)
+	}
+}
+
+impl<R: Read> Source for Streamed<R> {
+	fn skip(&mut self, len: u64) -> Result<(), ReadError> {
+		let skipped = try!(io::copy(&mut (&mut self.0).take(len), &mut io::sink()));
+		if skipped < len {
+			return Err(ReadError::Truncated);
+		}
+		Ok(())
+	}
+}
+
+/// A source which skips by seeking.
+struct Seekable<R: Read + Seek>(R);
+
+impl<R: Read + Seek> Read for Seekable<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.0.read(buf)
+	}
+}
+
+impl<R: Read + Seek> Source for Seekable<R> {
+	fn skip(&mut self, len: u64) -> Result<(), ReadError> {
+		if len == 0 {
+			return Ok(());
+		}
+		// Seeking past the end succeeds, so read the last skipped byte to
+		// be sure it exists.
+		try!(self.0.seek(SeekFrom::Current(len as i64 - 1)));
+		try!(read_all(&mut self.0, &mut [0]));
+		Ok(())
+	}
+}
+
+struct Reader<R: Source, E: ByteOrder> {
 	out: R,
+	header: Header,
+	options: ReadOptions,
+	/// The path of the function being read.
+	path: ProtoPath,
+	/// The byte range of each function read, if the source tracks its
+	/// position.
+	index: ProtoIndex,
+	endian: PhantomData<E>,
 }
 
-fn invalid<T, S: Into<Box<::std::error::Error + Send + Sync>>>(s: S) -> io::Result<T> {
-	Err(io::Error::new(io::ErrorKind::InvalidInput, s))
+fn invalid<T, S: Into<String>>(s: S) -> Result<T, ReadError> {
+	Err(ReadError::Invalid(s.into()))
 }
 
 macro_rules! check {
@@ -32,7 +451,7 @@ macro_rules! check {
 		let get = $get;
 		let want = $want;
 		if get != want {
-			return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+			return Err(ReadError::Invalid(format!(
 				"invalid {}, expected {:?} but got {:?}",
 				$note, want, get,
 			)));
@@ -40,57 +459,285 @@ macro_rules! check {
 	}}
 }
 
-impl<R: Read> Reader<R> {
-	fn read_all(&mut self, mut buf: &mut [u8]) -> io::Result<()> {
-		let mut start = 0;
-		let len = buf.len();
-		while start < len {
-			let n = try!(self.out.read(&mut buf[start..]));
-			if n == 0 {
-				return invalid("unexpected EOF");
-			}
-			start += n;
+fn read_all<R: Read>(read: &mut R, buf: &mut [u8]) -> Result<(), ReadError> {
+	let mut start = 0;
+	let len = buf.len();
+	while start < len {
+		let n = try!(read.read(&mut buf[start..]));
+		if n == 0 {
+			return Err(ReadError::Truncated);
 		}
-		Ok(())
+		start += n;
 	}
+	Ok(())
+}
 
-	fn read_header(&mut self) -> io::Result<()> {
-		let mut buffer = [0u8; 6];
-		try!(self.read_all(&mut buffer[..4]));
-		check!(&buffer[..4], SIGNATURE, "signature");
-		check!(try!(self.out.read_u8()), VERSION, "version");
-		check!(try!(self.out.read_u8()), FORMAT, "format");
-		try!(self.read_all(&mut buffer));
-		check!(&buffer, DATA, "test data");
-		check!(try!(self.out.read_u8()), size_of::<Int>() as u8, "sizeof(int)");
-		check!(try!(self.out.read_u8()), size_of::<Size>() as u8, "sizeof(size_t)");
-		check!(try!(self.out.read_u8()), size_of::<Instruction>() as u8, "sizeof(Instruction)");
-		check!(try!(self.out.read_u8()), size_of::<Integer>() as u8, "sizeof(Integer)");
-		check!(try!(self.out.read_u8()), size_of::<Number>() as u8, "sizeof(Number)");
-		check!(try!(self.out.read_i64::<E>()), TEST_INT, "test integer");
-		check!(try!(self.out.read_f64::<E>()), TEST_NUMBER, "test number");
+/// Read the first byte of a chunk, failing with `Eof` if there is none.
+fn read_first<R: Read>(read: &mut R) -> Result<u8, ReadError> {
+	let mut byte = [0];
+	if try!(read.read(&mut byte)) == 0 {
+		return Err(ReadError::Eof);
+	}
+	Ok(byte[0])
+}
+
+/// Read a header as `options` directs, adding any tolerated failures to
+/// `warnings` if `options.lenient_header` is set.
+fn read_header_with<R: Read>(read: &mut R, options: &ReadOptions, warnings: &mut Vec<HeaderWarning>)
+	-> Result<Header, ReadError>
+{
+	let lenient = if options.lenient_header { Some(warnings) } else { None };
+	if options.skip_to_signature {
+		try!(skip_to_signature(read));
+		read_header_lenient(&mut SIGNATURE.chain(read), lenient)
+	} else {
+		read_header_lenient(read, lenient)
+	}
+}
+
+/// Consume bytes up to and including the signature.
+fn skip_to_signature<R: Read>(read: &mut R) -> Result<(), ReadError> {
+	let mut window = [0u8; 4];
+	for count in 0..MAX_SKIPPED + SIGNATURE.len() {
+		let byte = if count == 0 { try!(read_first(read)) } else { try!(read.read_u8()) };
+		window = [window[1], window[2], window[3], byte];
+		if count + 1 >= SIGNATURE.len() && &window[..] == SIGNATURE {
+			return Ok(());
+		}
+	}
+	invalid(format!("signature not found within {} bytes", MAX_SKIPPED))
+}
+
+fn read_header<R: Read>(read: &mut R) -> Result<Header, ReadError> {
+	read_header_lenient(read, None)
+}
+
+/// Read the header, pushing the checks which fail to `warnings` rather
+/// than failing where the rest of the chunk can still be read.
+fn read_header_lenient<R: Read>(read: &mut R, mut warnings: Option<&mut Vec<HeaderWarning>>)
+	-> Result<Header, ReadError>
+{
+	let mut buffer = [0u8; 6];
+	buffer[0] = try!(read_first(read));
+	try!(read_all(read, &mut buffer[1..4]));
+	if &buffer[..3] == LUAJIT_SIGNATURE {
+		return Err(ReadError::LuaJit {
+			bc_version: buffer[3],
+			flags: try!(read.read_u8()),
+		});
+	}
+	check!(&buffer[..4], SIGNATURE, "signature");
+	let number = try!(read.read_u8());
+	let version = match Version::from_number(number) {
+		Some(Version::Lua52) => {
+			// The header layout diverges after the version, so don't try
+			// to read the rest of it.
+			return invalid("Lua 5.2 bytecode is not supported");
+		}
+		Some(version) => version,
+		None => return invalid(format!("invalid version, expected {:?} but got {:?}", VERSION, number)),
+	};
+	let format = try!(read.read_u8());
+	if format != FORMAT {
+		try!(tolerate(&mut warnings, HeaderWarning::Format(format)));
+	}
+	if version == Version::Lua51 {
+		return read_header_51(read);
+	}
+	try!(read_all(read, &mut buffer));
+	if &buffer[..] != DATA {
+		try!(tolerate(&mut warnings, HeaderWarning::Data(buffer)));
+	}
+	let int_size = try!(read_type_size(read, "int"));
+	let size_t_size = try!(read_type_size(read, "size_t"));
+	let instruction_size = try!(read_type_size(read, "Instruction"));
+	let integer_size = try!(read_type_size(read, "Integer"));
+	let number_size = try!(read_number_size(read));
+	let endian = try!(read_test_values(read, integer_size, number_size, &mut warnings));
+
+	let mut header = Header::native();
+	header.version = version;
+	header.endian = endian;
+	header.int_size = int_size;
+	header.size_t_size = size_t_size;
+	header.instruction_size = instruction_size;
+	header.integer_size = integer_size;
+	header.number_size = number_size;
+	Ok(header)
+}
+
+/// Read the rest of a Lua 5.1 header, which follows the format byte.
+///
+/// Lua 5.1 records the byte order in a field of its own rather than with
+/// test values, and flags builds whose `Number` is an integer type, which
+/// are not supported.
+fn read_header_51<R: Read>(read: &mut R) -> Result<Header, ReadError> {
+	let endian = match try!(read.read_u8()) {
+		0 => Endian::Big,
+		1 => Endian::Little,
+		other => return invalid(format!("invalid byte order {}", other)),
+	};
+	let int_size = try!(read_type_size(read, "int"));
+	let size_t_size = try!(read_type_size(read, "size_t"));
+	let instruction_size = try!(read_type_size(read, "Instruction"));
+	let number_size = try!(read_number_size(read));
+	if try!(read.read_u8()) != 0 {
+		return invalid("integral Lua 5.1 numbers are not supported");
+	}
+	Ok(Header {
+		version: Version::Lua51,
+		endian,
+		int_size,
+		size_t_size,
+		instruction_size,
+		integer_size: 8,
+		number_size,
+	})
+}
+
+/// Read the size of a C type, which must be 4 or 8 bytes.
+fn read_type_size<R: Read>(read: &mut R, name: &str) -> Result<u8, ReadError> {
+	let size = try!(read.read_u8());
+	if size != 4 && size != 8 {
+		return invalid(format!("unsupported sizeof({}) {}", name, size));
+	}
+	Ok(size)
+}
+
+/// Read the size of `Number`, which may be any nonzero size.
+fn read_number_size<R: Read>(read: &mut R) -> Result<u8, ReadError> {
+	let size = try!(read.read_u8());
+	if size == 0 {
+		return invalid("unsupported sizeof(Number) 0");
+	}
+	Ok(size)
+}
+
+/// Read the test values which follow the header's sizes, returning the
+/// byte order they reveal.
+///
+/// Each version writes different test values: 5.3 writes `LUAC_INT` and
+/// then `LUAC_NUM`, 5.2 writes only `LUAC_NUM`, and 5.1 writes neither,
+/// recording its byte order in an explicit header field instead, which
+/// `read_header_51` reads. Only 5.3's are read here.
+fn read_test_values<R: Read>(read: &mut R, integer_size: u8, number_size: u8,
+	warnings: &mut Option<&mut Vec<HeaderWarning>>) -> Result<Endian, ReadError>
+{
+	// The test integer reveals the byte order of the rest of the file.
+	let mut test = vec![0u8; integer_size as usize];
+	try!(read_all(read, &mut test));
+	let (little, big) = match integer_size {
+		4 => (LittleEndian::read_i32(&test) as Integer, BigEndian::read_i32(&test) as Integer),
+		_ => (LittleEndian::read_i64(&test), BigEndian::read_i64(&test)),
+	};
+	let endian = if little == TEST_INT {
+		Endian::Little
+	} else if big == TEST_INT {
+		Endian::Big
+	} else {
+		return invalid(format!("invalid test integer, expected {:?} in either byte order", TEST_INT));
+	};
+	let mut buffer = vec![0; number_size as usize];
+	try!(read_all(read, &mut buffer));
+	let number = match (number_size, endian) {
+		(8, Endian::Little) => LittleEndian::read_f64(&buffer),
+		(8, Endian::Big) => BigEndian::read_f64(&buffer),
+		(4, Endian::Little) => LittleEndian::read_f32(&buffer) as Number,
+		(4, Endian::Big) => BigEndian::read_f32(&buffer) as Number,
+		// Numbers of other sizes are kept as raw bytes, so there is no way
+		// to check the test number.
+		_ => return Ok(endian),
+	};
+	if number != TEST_NUMBER {
+		try!(tolerate(warnings, HeaderWarning::TestNumber(number)));
+	}
+	Ok(endian)
+}
+
+/// Record a failed header check in `warnings`, or fail if there are none.
+fn tolerate(warnings: &mut Option<&mut Vec<HeaderWarning>>, warning: HeaderWarning) -> Result<(), ReadError> {
+	match *warnings {
+		Some(ref mut warnings) => {
+			warnings.push(warning);
+			Ok(())
+		}
+		None => Err(ReadError::Invalid(warning.to_string())),
+	}
+}
+
+impl<R: Source, E: ByteOrder> Reader<R, E> {
+	fn new(read: R, header: Header, options: ReadOptions) -> Reader<R, E> {
+		Reader {
+			out: read,
+			header,
+			options,
+			path: Vec::new(),
+			index: ProtoIndex::new(),
+			endian: PhantomData,
+		}
+	}
+
+	fn read_all(&mut self, buf: &mut [u8]) -> Result<(), ReadError> {
+		read_all(&mut self.out, buf)
+	}
+
+	/// Read what comes between the header and the main function.
+	///
+	/// Lua 5.3 writes the main function's upvalue count there, which its
+	/// upvalue descriptors repeat, so it is discarded. Lua 5.1 writes
+	/// nothing.
+	fn read_prefix(&mut self) -> Result<(), ReadError> {
+		if self.header.version != Version::Lua51 {
+			try!(self.out.read_u8()); // discard upvals header
+		}
 		Ok(())
 	}
 
-	fn read_function(&mut self) -> io::Result<Function> {
+	/// Read the main function, following the header.
+	fn read_main(&mut self) -> Result<Function, ReadError> {
+		try!(self.read_prefix());
+		self.read_function()
+	}
+
+	fn read_function(&mut self) -> Result<Function, ReadError> {
+		if self.path.len() > self.options.max_depth {
+			return Err(ReadError::TooDeep(self.options.max_depth));
+		}
+		let start = self.out.position();
+		let function = try!(match self.header.version {
+			Version::Lua51 => self.read_function_51(),
+			_ => self.read_function_53(),
+		});
+		if self.options.check_upvalue_names {
+			if let Err(err) = function.check_upvalue_names() {
+				return invalid(err.to_string());
+			}
+		}
+		if let (Some(start), Some(end)) = (start, self.out.position()) {
+			self.index.insert(self.path.clone(), (start, end));
+		}
+		Ok(function)
+	}
+
+	/// Read a function in Lua 5.3's layout.
+	///
+	/// The fields come in this order: source, line defined, last line
+	/// defined, parameter count, vararg flag, max stack size, code,
+	/// constants, upvalue descriptors, nested functions, and then the debug
+	/// info: line info, local variables, and upvalue names. Nested
+	/// functions thus come before their parent's debug info, and the
+	/// upvalue descriptors are separated from their names.
+	fn read_function_53(&mut self) -> Result<Function, ReadError> {
 		Ok(Function {
-			source: try!(self.read_string()),
-			line_start: try!(self.out.read_i32::<E>()),
-			line_end: try!(self.out.read_i32::<E>()),
+			source: try!(self.read_string_opt()),
+			line_start: try!(self.read_int()),
+			line_end: try!(self.read_int()),
 			num_params: try!(self.out.read_u8()),
-			is_vararg: try!(self.out.read_u8()) != 0,
+			is_vararg: try!(self.out.read_u8()),
 			max_stack_size: try!(self.out.read_u8()),
-			code: try!(self.read_vec(|this| Ok(try!(this.out.read_u32::<E>())))),
-			constants: try!(self.read_vec(|this| Ok(match try!(this.out.read_u8()) {
-				0x00 => Constant::Nil,
-				0x01 => Constant::Boolean(try!(this.out.read_u8()) != 0),
-				0x03 => Constant::Float(try!(this.out.read_f64::<E>())),
-				0x13 => Constant::Int(try!(this.out.read_i64::<E>())),
-				0x04 => Constant::ShortString(try!(this.read_string())),
-				0x14 => Constant::LongString(try!(this.read_string())),
-				o => return invalid(format!("unknown constant type {}", o)),
-			}))),
-			upvalues: try!(self.read_vec(|this| {
+			code: try!(self.read_vec("code", |this| this.read_instruction())),
+			constants: try!(self.read_vec("constants", |this| this.read_constant())),
+			upvalues: try!(self.read_vec("upvalues", |this| {
 				let stack = try!(this.out.read_u8());
 				let idx = try!(this.out.read_u8());
 				Ok(match stack {
@@ -98,44 +745,270 @@ impl<R: Read> Reader<R> {
 					_ => Upvalue::Stack(idx),
 				})
 			})),
-			protos: try!(self.read_vec(|this| this.read_function())),
-			debug: Debug {
-				lineinfo: try!(self.read_vec(|this| Ok(try!(this.out.read_i32::<E>())))),
-				localvars: try!(self.read_vec(|this| Ok(LocalVar {
-					name: try!(this.read_string()),
-					start_pc: try!(this.out.read_i32::<E>()),
-					end_pc: try!(this.out.read_i32::<E>()),
-				}))),
-				upvalues: try!(self.read_vec(|this| this.read_string())),
-			},
+			protos: try!(self.read_protos()),
+			debug: try!(self.read_debug()),
+		})
+	}
+
+	/// Read a function in Lua 5.1's layout.
+	///
+	/// The fields come in this order: source, line defined, last line
+	/// defined, upvalue count, parameter count, vararg flags, max stack
+	/// size, code, constants, nested functions, and then the same debug
+	/// info as 5.3. There are no upvalue descriptors; instead each
+	/// `CLOSURE` is followed by a pseudo-instruction per upvalue of the
+	/// function it creates, from which the descriptors of the nested
+	/// functions are filled in.
+	fn read_function_51(&mut self) -> Result<Function, ReadError> {
+		let source = try!(self.read_string_opt());
+		let line_start = try!(self.read_int());
+		let line_end = try!(self.read_int());
+		let upvalue_count = try!(self.out.read_u8());
+		let mut function = Function {
+			source,
+			line_start,
+			line_end,
+			num_params: try!(self.out.read_u8()),
+			is_vararg: try!(self.out.read_u8()),
+			max_stack_size: try!(self.out.read_u8()),
+			code: try!(self.read_vec("code", |this| this.read_instruction())),
+			constants: try!(self.read_vec("constants", |this| this.read_constant())),
+			upvalues: (0..upvalue_count).map(Upvalue::Outer).collect(),
+			protos: try!(self.read_protos()),
+			debug: try!(self.read_debug()),
+		};
+		try!(describe_upvalues_51(&mut function));
+		Ok(function)
+	}
+
+	/// Read a function's line info, local variables, and upvalue names.
+	fn read_debug(&mut self) -> Result<Debug, ReadError> {
+		Ok(Debug {
+			lineinfo: try!(self.read_vec("lineinfo", |this| this.read_int())),
+			localvars: try!(self.read_vec("localvars", |this| Ok(LocalVar {
+				name: try!(this.read_string()),
+				start_pc: try!(this.read_int()),
+				end_pc: try!(this.read_int()),
+			}))),
+			upvalues: try!(self.read_vec("upvalue names", |this| this.read_string())),
+		})
+	}
+
+	/// Read a function's nested functions, keeping track of their paths.
+	fn read_protos(&mut self) -> Result<Vec<Function>, ReadError> {
+		self.path.push(0);
+		let protos = self.read_vec("protos", |this| {
+			let proto = try!(this.read_function());
+			*this.path.last_mut().unwrap() += 1;
+			Ok(proto)
+		});
+		self.path.pop();
+		protos
+	}
+
+	/// Read the main function along with the byte range of each function.
+	fn read_indexed(mut self) -> Result<(Function, ProtoIndex), ReadError> {
+		let main = try!(self.read_main());
+		Ok((main, self.index))
+	}
+
+	/// Read only as far as the function's constants, skipping its code.
+	fn read_constants_only(&mut self) -> Result<Vec<Constant>, ReadError> {
+		try!(self.read_prefix());
+		try!(self.read_string_opt()); // source
+		try!(self.read_int()); // line_start
+		try!(self.read_int()); // line_end
+		// Lua 5.1's upvalue count, then num_params, is_vararg, max_stack_size
+		let fields = if self.header.version == Version::Lua51 { 4 } else { 3 };
+		try!(self.read_all(&mut [0; 4][..fields]));
+		let code_len = try!(self.read_len("code"));
+		try!(self.out.skip(code_len as u64 * self.header.instruction_size as u64));
+		self.read_vec("constants", |this| this.read_constant())
+	}
+
+	fn read_constant(&mut self) -> Result<Constant, ReadError> {
+		// Before 5.3, there are no integers, and a single tag for strings.
+		let lua53 = self.header.version >= Version::Lua53;
+		Ok(match try!(self.out.read_u8()) {
+			TAG_NIL => Constant::Nil,
+			TAG_BOOLEAN => Constant::Boolean(try!(self.out.read_u8()) != 0),
+			TAG_FLOAT => try!(self.read_number()),
+			TAG_INT if lua53 => Constant::Int(try!(self.read_integer())),
+			TAG_SHORT_STRING => Constant::ShortString(try!(self.read_string())),
+			TAG_LONG_STRING if lua53 => Constant::LongString(try!(self.read_string())),
+			// The length of an unknown constant can't be known, so there
+			// is no way to skip it and continue.
+			o => return invalid(format!("unknown constant type {:#04x}", o)),
 		})
 	}
 
 	#[inline]
-	fn read_vec<F, T>(&mut self, f: F) -> io::Result<Vec<T>>
-		where F: Fn(&mut Self) -> io::Result<T>
+	fn read_vec<F, T>(&mut self, field: &'static str, f: F) -> Result<Vec<T>, ReadError>
+		where F: Fn(&mut Self) -> Result<T, ReadError>
 	{
-		let len = try!(self.out.read_u32::<E>());
+		let len = try!(self.read_len(field));
 		(0..len).map(|_| f(self)).collect()
 	}
 
-	fn read_string(&mut self) -> io::Result<String> {
+	fn read_len(&mut self, field: &'static str) -> Result<Int, ReadError> {
+		let len = try!(self.read_int());
+		if len < 0 {
+			return invalid(format!("invalid vector length {}", len));
+		}
+		try!(self.check_len(field, len as u64));
+		Ok(len)
+	}
+
+	fn check_len(&self, field: &'static str, len: u64) -> Result<(), ReadError> {
+		if len > self.options.max_len {
+			return Err(ReadError::ImplausibleLength { field, len });
+		}
+		Ok(())
+	}
+
+	fn read_int(&mut self) -> Result<Int, ReadError> {
+		if self.header.int_size == 4 {
+			Ok(try!(self.out.read_i32::<E>()) as Int)
+		} else {
+			let value = try!(self.out.read_i64::<E>());
+			if value as Int as i64 != value {
+				return invalid(format!("int {} out of range", value));
+			}
+			Ok(value as Int)
+		}
+	}
+
+	fn read_size_t(&mut self) -> Result<u64, ReadError> {
+		if self.header.size_t_size == 4 {
+			Ok(try!(self.out.read_u32::<E>()) as u64)
+		} else {
+			Ok(try!(self.out.read_u64::<E>()))
+		}
+	}
+
+	fn read_size(&mut self) -> Result<usize, ReadError> {
+		let value = try!(self.read_size_t());
+		if value == 0 || value > usize::MAX as u64 {
+			return invalid(format!("invalid string size {}", value));
+		}
+		Ok(value as usize)
+	}
+
+	fn read_integer(&mut self) -> Result<Integer, ReadError> {
+		if self.header.integer_size == 4 {
+			Ok(try!(self.out.read_i32::<E>()) as Integer)
+		} else {
+			Ok(try!(self.out.read_i64::<E>()))
+		}
+	}
+
+	fn read_instruction(&mut self) -> Result<Instruction, ReadError> {
+		if self.header.instruction_size == 4 {
+			Ok(try!(self.out.read_u32::<E>()))
+		} else {
+			let value = try!(self.out.read_u64::<E>());
+			if value as Instruction as u64 != value {
+				return invalid(format!("instruction {:#x} out of range", value));
+			}
+			Ok(value as Instruction)
+		}
+	}
+
+	fn read_number(&mut self) -> Result<Constant, ReadError> {
+		match self.header.number_size {
+			8 => Ok(Constant::Float(try!(self.out.read_f64::<E>()))),
+			4 => Ok(Constant::Float(try!(self.out.read_f32::<E>()) as Number)),
+			size => {
+				let mut buffer = vec![0u8; size as usize];
+				try!(self.read_all(&mut buffer));
+				Ok(Constant::RawFloat(buffer))
+			}
+		}
+	}
+
+	fn read_string(&mut self) -> Result<LuaString, ReadError> {
+		self.read_string_opt().map(|s| s.unwrap_or_default())
+	}
+
+	fn read_string_opt(&mut self) -> Result<Option<LuaString>, ReadError> {
+		if self.header.version == Version::Lua51 {
+			return self.read_string_opt_51();
+		}
 		let first = try!(self.out.read_u8());
 		if first == 0 {
-			Ok(String::new())
+			Ok(None)
 		} else {
-			let len = if first < 0xff {
+			let size = if first < 0xff {
 				first as usize
 			} else {
-				try!(self.out.read_u32::<E>()) as usize
-			} - 1;
+				try!(self.read_size())
+			};
+			let len = size - 1;
+			try!(self.check_len("string", len as u64));
 			let mut buffer = vec![0u8; len];
 			try!(self.read_all(&mut buffer));
-			// TODO: May need to return a Vec<u8> rather than String
-			match String::from_utf8(buffer) {
-				Ok(s) => Ok(s),
-				Err(_) => invalid("not utf8"),
+			let mut string = LuaString::new(buffer);
+			if self.options.preserve_encoding && first == 0xff && size < 0xff {
+				string.set_long_length(true);
+			}
+			Ok(Some(string))
+		}
+	}
+	/// Read a string in Lua 5.1's form: its length plus one as a `size_t`,
+	/// or zero for no string, and then its bytes followed by a NUL.
+	fn read_string_opt_51(&mut self) -> Result<Option<LuaString>, ReadError> {
+		let size = try!(self.read_size_t());
+		if size == 0 {
+			return Ok(None);
+		} else if size > usize::MAX as u64 {
+			return invalid(format!("invalid string size {}", size));
+		}
+		let len = size as usize - 1;
+		try!(self.check_len("string", len as u64));
+		let mut buffer = vec![0u8; len + 1];
+		try!(self.read_all(&mut buffer));
+		if buffer.pop() != Some(0) {
+			return invalid("string is not terminated by a NUL");
+		}
+		Ok(Some(LuaString::new(buffer)))
+	}
+}
+
+/// Fill in the upvalue descriptors of a Lua 5.1 function's nested
+/// functions from the `MOVE` or `GETUPVAL` pseudo-instructions which follow
+/// each `CLOSURE`.
+///
+/// The upvalues of a function which no `CLOSURE` creates, such as the main
+/// function, are left as `Upvalue::Outer` of their own index.
+fn describe_upvalues_51(function: &mut Function) -> Result<(), ReadError> {
+	let op_mask = (1 << SIZE_OP) - 1;
+	let code = &function.code;
+	let protos = &mut function.protos;
+	for (pc, &ins) in code.iter().enumerate() {
+		if ins & op_mask != OP51_CLOSURE {
+			continue;
+		}
+		let index = (ins >> (SIZE_OP + SIZE_A)) as usize;
+		let proto = match protos.get_mut(index) {
+			Some(proto) => proto,
+			None => return invalid(format!("CLOSURE at {} refers to missing function {}", pc, index)),
+		};
+		for (i, upvalue) in proto.upvalues.iter_mut().enumerate() {
+			let pseudo = match code.get(pc + 1 + i) {
+				Some(&pseudo) => pseudo,
+				None => return invalid(format!("CLOSURE at {} is missing upvalue {}", pc, i)),
+			};
+			let b = pseudo >> (SIZE_OP + SIZE_A + SIZE_C);
+			if b > MAXARG_A {
+				return invalid(format!("upvalue {} of CLOSURE at {} has index {}", i, pc, b));
 			}
+			*upvalue = match pseudo & op_mask {
+				OP51_MOVE => Upvalue::Stack(b as u8),
+				OP51_GETUPVAL => Upvalue::Outer(b as u8),
+				op => return invalid(format!(
+					"upvalue {} of CLOSURE at {} is described by opcode {}", i, pc, op)),
+			};
 		}
 	}
+	Ok(())
 }
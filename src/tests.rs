@@ -0,0 +1,969 @@
+//! Tests of reading and writing bytecode.
+
+use std::io;
+use std::ops::Range;
+
+use byteorder::{ByteOrder, LittleEndian};
+use bytecode::{encode, encode_bx, Opcode};
+use super::*;
+
+/// Read `bytes`, write the chunk back out, and read that again, checking
+/// that the written bytes match the input and both reads agree.
+fn assert_roundtrip(bytes: &[u8]) -> Chunk {
+	let chunk = read_chunk(bytes).expect("failed to read chunk");
+	let written = chunk.to_bytes().expect("failed to write chunk");
+	assert_eq!(&written[..], bytes, "written bytes differ from those read");
+	let reread = read_chunk(&written[..]).expect("failed to reread chunk");
+	assert_eq!(reread, chunk, "reread chunk differs from that first read");
+	chunk
+}
+
+/// A main function exercising every section of the format: a source,
+/// constants of each type, upvalues, a nested function, and debug info.
+fn sample() -> Function {
+	Function {
+		source: Some("@test.lua".into()),
+		line_start: 0,
+		line_end: 0,
+		num_params: 0,
+		is_vararg: 1,
+		max_stack_size: 2,
+		code: vec![
+			encode_bx(Opcode::Closure, 0, 0),
+			encode(Opcode::Return, 0, 1, 0),
+		],
+		constants: vec![
+			Constant::ShortString("print".into()),
+			Constant::Int(5),
+			Constant::Float(1.5),
+			Constant::Nil,
+			Constant::Boolean(true),
+			Constant::LongString("x".repeat(300).into()),
+		],
+		upvalues: vec![Upvalue::Stack(0)],
+		protos: vec![Function {
+			source: None,
+			line_start: 1,
+			line_end: 3,
+			num_params: 1,
+			is_vararg: 0,
+			max_stack_size: 2,
+			code: vec![encode(Opcode::Return, 0, 1, 0)],
+			constants: vec![],
+			upvalues: vec![Upvalue::Outer(0)],
+			protos: vec![],
+			debug: Debug {
+				lineinfo: vec![3],
+				localvars: vec![LocalVar { name: "a".into(), start_pc: 0, end_pc: 1 }],
+				upvalues: vec!["_ENV".into()],
+			},
+		}],
+		debug: Debug {
+			lineinfo: vec![3, 3],
+			localvars: vec![],
+			upvalues: vec!["_ENV".into()],
+		},
+	}
+}
+
+/// The bytes of `sample()` written with `header`.
+fn sample_bytes(header: Header) -> Vec<u8> {
+	Chunk { header, main: sample() }.to_bytes().unwrap()
+}
+
+#[test]
+fn roundtrip_default_header() {
+	let chunk = assert_roundtrip(&sample_bytes(Header::default()));
+	assert_eq!(chunk.main, sample());
+}
+
+#[test]
+fn content_hash() {
+	let chunk = Chunk { header: Header::default(), main: sample() };
+	let hash = chunk.content_hash().unwrap();
+	assert_eq!(hash, chunk.clone().content_hash().unwrap());
+	assert_eq!(chunk.main.content_hash().unwrap(), hash);
+
+	let big = Chunk { header: Header { endian: Endian::Big, ..chunk.header }, ..chunk.clone() };
+	assert!(big.content_hash().unwrap() != hash);
+
+	let mut changed = chunk.clone();
+	changed.main.constants.push(Constant::Int(6));
+	assert!(changed.content_hash().unwrap() != hash);
+
+	let unwritable = Chunk { header: Header { int_size: 3, ..chunk.header }, ..chunk };
+	assert!(unwritable.content_hash().is_err());
+}
+
+#[test]
+fn write_chunk_to_slice_uses_header() {
+	let chunk = Chunk { header: Header { endian: Endian::Big, size_t_size: 4, ..Header::default() }, main: sample() };
+	let bytes = chunk.to_bytes().unwrap();
+	assert_eq!(chunk.serialized_len().unwrap(), bytes.len());
+
+	let mut buf = vec![0xaa; bytes.len() + 2];
+	assert_eq!(write_chunk_to_slice(&mut buf, &chunk).unwrap(), bytes.len());
+	assert_eq!(&buf[..bytes.len()], &bytes[..]);
+	assert_eq!(&buf[bytes.len()..], &[0xaa, 0xaa]);
+
+	let mut short = vec![0; bytes.len() - 1];
+	match write_chunk_to_slice(&mut short, &chunk) {
+		Err(WriteError::BufferTooSmall { needed, available }) => {
+			assert_eq!(needed, bytes.len());
+			assert_eq!(available, bytes.len() - 1);
+		}
+		other => panic!("unexpected result {:?}", other),
+	}
+	assert!(short.iter().all(|&b| b == 0));
+}
+
+#[test]
+fn detect_byte_order() {
+	for &endian in &[Endian::Little, Endian::Big] {
+		for &int_size in &[4, 8] {
+			for &size_t_size in &[4, 8] {
+				for &instruction_size in &[4, 8] {
+					for &number_size in &[4, 8] {
+						let header = Header {
+							version: Version::Lua53,
+							endian,
+							int_size,
+							size_t_size,
+							instruction_size,
+							integer_size: 8,
+							number_size,
+						};
+						let chunk = assert_roundtrip(&sample_bytes(header));
+						assert_eq!(chunk.header, header);
+						assert_eq!(chunk.main, sample());
+					}
+				}
+			}
+		}
+	}
+}
+
+#[test]
+fn read_big_endian() {
+	let header = Header { endian: Endian::Big, ..Header::default() };
+	let bytes = sample_bytes(header);
+	// The test integer 0x5678 follows the 12 bytes before the sizes and the
+	// five sizes themselves.
+	assert_eq!(&bytes[17..25], &[0, 0, 0, 0, 0, 0, 0x56, 0x78]);
+	let chunk = assert_roundtrip(&bytes);
+	assert_eq!(chunk.header.endian, Endian::Big);
+}
+
+#[test]
+fn number_size_must_be_nonzero() {
+	assert!(Header { number_size: 16, ..Header::default() }.check_writable().is_ok());
+	assert!(Header { number_size: 0, ..Header::default() }.check_writable().is_err());
+
+	let mut bytes = sample_bytes(Header::default());
+	bytes[16] = 0; // sizeof(Number)
+	match read_chunk(&bytes[..]) {
+		Err(ReadError::Invalid(ref msg)) => assert_eq!(msg, "unsupported sizeof(Number) 0"),
+		other => panic!("unexpected result {:?}", other),
+	}
+}
+
+#[test]
+fn roundtrip_unusual_number_size() {
+	let header = Header { number_size: 16, ..Header::default() };
+	let mut raw = vec![0; 16];
+	raw[0] = 0x80;
+	raw[9] = 0x40;
+	let mut main = Function::empty_main();
+	main.constants = vec![Constant::RawFloat(raw), Constant::Int(-1)];
+	let bytes = Chunk { header, main: main.clone() }.to_bytes().unwrap();
+	// The test number takes the header's 16 bytes, followed by the main
+	// function's upvalue count.
+	assert_eq!(&bytes[25..41], &[0; 16]);
+	assert_eq!(bytes[41], 1);
+
+	let chunk = assert_roundtrip(&bytes);
+	assert_eq!(chunk.header.number_size, 16);
+	assert_eq!(chunk.main, main);
+
+	// A raw float of the wrong size, or a float which has no encoding in
+	// that size, can't be written.
+	let mut wrong = chunk.clone();
+	wrong.main.constants = vec![Constant::RawFloat(vec![0; 8])];
+	assert!(wrong.to_bytes().is_err());
+	wrong.main.constants = vec![Constant::Float(1.0)];
+	assert!(wrong.to_bytes().is_err());
+}
+
+#[test]
+fn basic_block_successors() {
+	use bytecode::encode_sbx;
+	let mut function = Function::empty_main();
+	function.code = vec![
+		encode(Opcode::Eq, 0, 0, 1),
+		encode_sbx(Opcode::Jump, 0, 1),
+		encode(Opcode::LoadBool, 0, 1, 1),
+		encode(Opcode::LoadBool, 0, 0, 0),
+		encode(Opcode::Return, 0, 2, 0),
+	];
+	let blocks = function.basic_blocks();
+	let starts: Vec<usize> = blocks.iter().map(|block| block.start).collect();
+	assert_eq!(starts, vec![0, 1, 2, 3, 4]);
+	let successors: Vec<&[usize]> = blocks.iter().map(|block| &block.successors[..]).collect();
+	// The test either continues to its jump or skips it.
+	assert_eq!(successors[0], &[1, 2]);
+	assert_eq!(successors[1], &[3]);
+	// LOADBOOL with C set only ever skips the next instruction.
+	assert_eq!(successors[2], &[4]);
+	assert_eq!(successors[3], &[4]);
+	assert_eq!(successors[4], &[] as &[usize]);
+}
+
+#[test]
+fn roundtrip_non_utf8_strings() {
+	let mut main = Function::empty_main();
+	main.source = Some(LuaString::new(&b"@\xff.lua"[..]));
+	main.constants = vec![
+		Constant::ShortString(LuaString::new(&b"\xc3\x28"[..])),
+		Constant::LongString(LuaString::new(vec![0xfe; 300])),
+	];
+	let chunk = assert_roundtrip(&Chunk { header: Header::default(), main: main.clone() }.to_bytes().unwrap());
+	assert_eq!(chunk.main, main);
+	assert_eq!(chunk.main.source.as_ref().unwrap().as_bytes(), b"@\xff.lua");
+	assert!(chunk.main.source.as_ref().unwrap().as_str().is_none());
+}
+
+#[test]
+fn oversized_lengths() {
+	let mut main = Function::empty_main();
+	main.upvalues = vec![Upvalue::Stack(0); 256];
+	match Chunk::new(main).to_bytes() {
+		Err(WriteError::ValueDoesNotFit { field, value }) => {
+			assert_eq!(field, "upvalue count");
+			assert_eq!(value, 256);
+		}
+		other => panic!("unexpected result {:?}", other),
+	}
+
+	let chunk = Chunk { header: Header::default(), main: Function::empty_main() };
+	let mut bytes = chunk.to_bytes().unwrap();
+	// The code length follows the header, the upvalue count, the byte for
+	// no source, the two line numbers, and three single bytes.
+	assert_eq!(&bytes[46..50], &[1, 0, 0, 0]);
+	bytes[46..50].copy_from_slice(&[0xff, 0xff, 0xff, 0x7f]);
+	match read_chunk(&bytes[..]) {
+		Err(ReadError::ImplausibleLength { field, len }) => {
+			assert_eq!(field, "code");
+			assert_eq!(len, 0x7fffffff);
+		}
+		other => panic!("unexpected result {:?}", other),
+	}
+}
+
+#[test]
+fn roundtrip_int_size_8() {
+	let header = Header { int_size: 8, ..Header::default() };
+	let chunk = Chunk { header, main: Function::empty_main() };
+	let bytes = chunk.to_bytes().unwrap();
+	// The line numbers and the code length, like every int, take 8 bytes.
+	assert_eq!(&bytes[35..51], &[0; 16]);
+	assert_eq!(&bytes[54..62], &[1, 0, 0, 0, 0, 0, 0, 0]);
+	let read = assert_roundtrip(&bytes);
+	assert_eq!(read.header.int_size, 8);
+	assert_eq!(read.main, chunk.main);
+	assert_roundtrip(&sample_bytes(header));
+}
+
+#[test]
+fn roundtrip_instruction_size_8() {
+	let header = Header { instruction_size: 8, ..Header::default() };
+	let chunk = Chunk { header, main: Function::empty_main() };
+	let bytes = chunk.to_bytes().unwrap();
+	// The code length is followed by the single RETURN in 8 bytes.
+	assert_eq!(&bytes[46..50], &[1, 0, 0, 0]);
+	assert_eq!(&bytes[50..58], &[0x26, 0, 0x80, 0, 0, 0, 0, 0]);
+	let read = assert_roundtrip(&bytes);
+	assert_eq!(read.header.instruction_size, 8);
+	assert_eq!(read.main, chunk.main);
+	assert_roundtrip(&sample_bytes(header));
+}
+
+#[test]
+fn assemble_disassembled() {
+	let mut main = sample();
+	main.constants.extend(vec![
+		Constant::Float(f64::from_bits(0x7ff8_0000_0000_0001)),
+		Constant::Float(-0.0),
+		Constant::Float(f64::NEG_INFINITY),
+		Constant::Float(0.1),
+		Constant::Int(i64::MIN),
+		Constant::ShortString("a\0b".into()),
+		Constant::ShortString("héllo \u{1f600}".into()),
+		Constant::ShortString(LuaString::new(&b"\xff\"\\\n"[..])),
+		Constant::LongString("y".repeat(254).into()),
+		Constant::RawFloat(vec![1, 2, 3]),
+	]);
+	main.code.insert(1, 0xffff_ffff);
+	main.protos[0].protos.push(Function {
+		source: Some("=nested".into()),
+		constants: vec![Constant::Int(-1)],
+		..sample().protos[0].clone()
+	});
+	// Debug information isn't part of the text format, though the source is.
+	fn clear_debug(function: &mut Function) {
+		function.debug = Debug::none();
+		for proto in &mut function.protos {
+			clear_debug(proto);
+		}
+	}
+	clear_debug(&mut main);
+
+	let text = main.disassemble();
+	let assembled = assemble(&text).unwrap();
+	assert_eq!(assembled, main);
+	assert_eq!(assembled.disassemble(), text);
+}
+
+#[test]
+fn upvalue_names() {
+	let mut function = sample();
+	assert_eq!(function.upvalue_count(), 1);
+	assert_eq!(function.check_upvalue_names(), Ok(()));
+	function.debug.upvalues.clear();
+	assert_eq!(function.check_upvalue_names(), Ok(()));
+	function.debug.upvalues = vec!["_ENV".into(), "x".into()];
+	assert_eq!(function.check_upvalue_names(), Err(UpvalueNamesError { names: 2, upvalues: 1 }));
+	assert!(!function.upvalue_names_match());
+
+	let bytes = Chunk { header: Header::default(), main: function }.to_bytes().unwrap();
+	match read_chunk(&bytes[..]) {
+		Err(ReadError::Invalid(ref msg)) => assert_eq!(msg, "2 upvalue names for 1 upvalues"),
+		other => panic!("unexpected result {:?}", other),
+	}
+}
+
+#[test]
+fn vararg_flags() {
+	let mut function = Function::empty_main();
+	assert!(function.has_vararg());
+	assert!(!function.has_arg(Version::Lua53));
+
+	function.is_vararg = VARARG_ISVARARG | VARARG_HASARG | VARARG_NEEDSARG;
+	assert!(function.has_vararg());
+	assert!(function.has_arg(Version::Lua51));
+	assert!(function.needs_arg(Version::Lua51));
+	assert!(!function.needs_arg(Version::Lua53));
+
+	// The raw byte is kept through both the binary and text formats.
+	let chunk = Chunk { header: Header::default(), main: function };
+	assert_eq!(assert_roundtrip(&chunk.to_bytes().unwrap()), chunk);
+	assert_eq!(assemble(&chunk.main.disassemble()).unwrap().is_vararg, 7);
+	assert!(assemble("function\nvararg true\nend").is_err());
+
+	function = Function::empty_main();
+	function.is_vararg = 0;
+	assert!(!function.has_vararg());
+}
+
+#[test]
+fn function_field_order() {
+	let ret = encode(Opcode::Return, 0, 1, 0);
+	let nested = Function {
+		line_start: 5,
+		line_end: 6,
+		is_vararg: 0,
+		upvalues: vec![],
+		..Function::empty_main()
+	};
+	let main = Function {
+		source: Some("@a".into()),
+		line_start: 1,
+		line_end: 2,
+		num_params: 3,
+		is_vararg: 1,
+		max_stack_size: 4,
+		code: vec![ret],
+		constants: vec![Constant::Int(5)],
+		upvalues: vec![Upvalue::Stack(0)],
+		protos: vec![nested],
+		debug: Debug {
+			lineinfo: vec![7],
+			localvars: vec![LocalVar { name: "b".into(), start_pc: 0, end_pc: 1 }],
+			upvalues: vec!["_ENV".into()],
+		},
+	};
+	let expected: &[&[u8]] = &[
+		&[3, b'@', b'a'], // source
+		&[1, 0, 0, 0, 2, 0, 0, 0], // lines
+		&[3], // params
+		&[1], // vararg
+		&[4], // maxstack
+		&[1, 0, 0, 0, 0x26, 0, 0x80, 0], // code
+		&[1, 0, 0, 0, 0x13, 5, 0, 0, 0, 0, 0, 0, 0], // constants
+		&[1, 0, 0, 0, 1, 0], // upvalues
+		&[1, 0, 0, 0], // protos
+		&[0, 5, 0, 0, 0, 6, 0, 0, 0, 0, 0, 2], // nested source, lines, params, vararg, maxstack
+		&[1, 0, 0, 0, 0x26, 0, 0x80, 0], // nested code
+		&[0; 24], // nested constants, upvalues, protos, and debug
+		&[1, 0, 0, 0, 7, 0, 0, 0], // lineinfo
+		&[1, 0, 0, 0, 2, b'b', 0, 0, 0, 0, 1, 0, 0, 0], // localvars
+		&[1, 0, 0, 0, 5, b'_', b'E', b'N', b'V'], // upvalue names
+	];
+	let expected = expected.concat();
+
+	let bytes = Chunk { header: Header::default(), main: main.clone() }.to_bytes().unwrap();
+	// Skip the header and the main function's upvalue count.
+	assert_eq!(&bytes[34..], &expected[..]);
+	assert_eq!(assert_roundtrip(&bytes).main, main);
+}
+
+#[test]
+fn roundtrip_f32_numbers() {
+	let header = Header::default().with_number_size(4);
+	let mut main = Function::empty_main();
+	main.constants = vec![Constant::Float(1.5), Constant::Float(-0.25), Constant::Float(0.1)];
+	let bytes = Chunk { header, main }.to_bytes().unwrap();
+	// The test number is written as a 4-byte float too.
+	let mut test_number = [0; 4];
+	LittleEndian::write_f32(&mut test_number, TEST_NUMBER as f32);
+	assert_eq!(&bytes[25..29], &test_number);
+
+	let chunk = assert_roundtrip(&bytes);
+	assert_eq!(chunk.header.number_size, 4);
+	// Values exact in an f32 are unchanged, while others are rounded to
+	// the nearest f32 on writing.
+	assert_eq!(chunk.main.constants[..2], [Constant::Float(1.5), Constant::Float(-0.25)]);
+	assert_eq!(chunk.main.constants[2], Constant::Float(0.1f32 as f64));
+	assert!(chunk.main.constants[2] != Constant::Float(0.1));
+}
+
+#[test]
+fn insert_instruction_keeps_pairs() {
+	use bytecode::encode_sbx;
+	let nop = encode(Opcode::Move, 0, 0, 0);
+	let mut function = Function::empty_main();
+	function.constants = vec![Constant::Int(1)];
+	function.code = vec![
+		encode(Opcode::Test, 0, 0, 1),
+		encode_sbx(Opcode::Jump, 0, 1),
+		encode(Opcode::LoadBool, 0, 1, 1),
+		encode(Opcode::LoadBool, 0, 0, 0),
+		encode_bx(Opcode::LoadKX, 0, 0),
+		encode(Opcode::ExtraArg, 0, 0, 0),
+		encode(Opcode::Return, 0, 1, 0),
+	];
+	let original = function.clone();
+	for &(pc, culprit) in &[(1, 0), (3, 2), (5, 4)] {
+		assert_eq!(function.insert_instruction(pc, nop), Err(EditError::SplitsPair(culprit)));
+	}
+	assert_eq!(function.insert_instruction(8, nop), Err(EditError::NoSuchInstruction(8)));
+	assert_eq!(function, original);
+
+	// Inserting before a pair, or after it, is fine.
+	function.insert_instruction(4, nop).unwrap();
+	function.insert_instruction(7, nop).unwrap();
+	function.insert_instruction(0, nop).unwrap();
+	assert_eq!(function.code.len(), 10);
+}
+
+#[test]
+fn remove_instructions_keeps_pairs() {
+	use bytecode::encode_sbx;
+	let mut function = Function::empty_main();
+	function.constants = vec![Constant::Int(1)];
+	function.code = vec![
+		encode(Opcode::Eq, 0, 0, 1),
+		encode_sbx(Opcode::Jump, 0, 1),
+		encode(Opcode::LoadBool, 0, 1, 1),
+		encode(Opcode::LoadBool, 0, 0, 0),
+		encode(Opcode::Move, 1, 0, 0),
+		encode_bx(Opcode::LoadKX, 0, 0),
+		encode(Opcode::ExtraArg, 0, 0, 0),
+		encode(Opcode::Return, 0, 1, 0),
+	];
+	let original = function.clone();
+	for &(ref range, culprit) in &[(1..2, 0), (3..4, 2), (6..7, 5), (6..8, 5)] {
+		assert_eq!(function.remove_instructions(range.clone()), Err(EditError::SplitsPair(culprit)));
+	}
+	assert_eq!(function, original);
+
+	// Removing both halves of a pair, or nothing, is fine.
+	function.remove_instructions(1..1).unwrap();
+	function.remove_instructions(5..7).unwrap();
+	function.remove_instructions(0..2).unwrap();
+	assert_eq!(function.code, vec![original.code[2], original.code[3], original.code[4], original.code[7]]);
+}
+
+#[test]
+fn remove_instructions_out_of_range() {
+	let mut function = Function::empty_main();
+	let original = function.clone();
+	assert_eq!(function.remove_instructions(0..2), Err(EditError::NoSuchInstruction(1)));
+	assert_eq!(function.remove_instructions(Range { start: 1, end: 0 }), Err(EditError::NoSuchInstruction(1)));
+	assert_eq!(function, original);
+	function.remove_instructions(1..1).unwrap();
+	function.remove_instructions(0..1).unwrap();
+	assert!(function.code.is_empty());
+}
+
+#[test]
+fn constant_encodings() {
+	fn string(tag: u8, prefix: &[u8], body: &[u8]) -> Vec<u8> {
+		[&[tag][..], prefix, body].concat()
+	}
+	let long = "y".repeat(300);
+	let cases: Vec<(Constant, Vec<u8>)> = vec![
+		(Constant::Nil, vec![0]),
+		(Constant::Boolean(false), vec![1, 0]),
+		(Constant::Boolean(true), vec![1, 1]),
+		(Constant::Float(-1.5), vec![3, 0, 0, 0, 0, 0, 0, 0xf8, 0xbf]),
+		(Constant::Float(0.25), vec![3, 0, 0, 0, 0, 0, 0, 0xd0, 0x3f]),
+		(Constant::Int(-2), vec![0x13, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+		(Constant::Int(i64::MIN), vec![0x13, 0, 0, 0, 0, 0, 0, 0, 0x80]),
+		(Constant::ShortString("".into()), vec![4, 1]),
+		(Constant::ShortString("a\0b".into()), vec![4, 4, b'a', 0, b'b']),
+		// A length plus one below 0xff fits in the single byte.
+		(Constant::ShortString("y".repeat(253).into()), string(4, &[254], &long.as_bytes()[..253])),
+		// From there, 0xff is followed by the length plus one as a size_t.
+		(Constant::ShortString("y".repeat(254).into()), string(4, &[0xff, 255, 0, 0, 0, 0, 0, 0, 0], &long.as_bytes()[..254])),
+		(Constant::LongString("y".repeat(255).into()), string(0x14, &[0xff, 0, 1, 0, 0, 0, 0, 0, 0], &long.as_bytes()[..255])),
+		(Constant::LongString(long.clone().into()), string(0x14, &[0xff, 0x2d, 1, 0, 0, 0, 0, 0, 0], long.as_bytes())),
+	];
+	for (constant, expected) in cases {
+		let mut main = Function::empty_main();
+		main.constants = vec![constant];
+		let bytes = Chunk { header: Header::default(), main: main.clone() }.to_bytes().unwrap();
+		// The constants follow the header, upvalue count, source, lines,
+		// three bytes, and the code's length and single instruction.
+		assert_eq!(&bytes[54..58], &[1, 0, 0, 0]);
+		assert_eq!(&bytes[58..58 + expected.len()], &expected[..], "{:?}", main.constants[0]);
+		assert_eq!(assert_roundtrip(&bytes).main, main);
+	}
+}
+
+#[test]
+fn long_string_length_width() {
+	let mut main = Function::empty_main();
+	main.constants = vec![Constant::LongString("z".repeat(300).into())];
+	for &(size_t_size, ref prefix) in &[(8, vec![0x14, 0xff, 0x2d, 1, 0, 0, 0, 0, 0, 0]), (4, vec![0x14, 0xff, 0x2d, 1, 0, 0])] {
+		let header = Header { size_t_size, ..Header::default() };
+		let bytes = Chunk { header, main: main.clone() }.to_bytes().unwrap();
+		assert_eq!(&bytes[58..58 + prefix.len()], &prefix[..]);
+		// The string is followed by the single upvalue and four empty lists.
+		assert_eq!(bytes.len(), 58 + prefix.len() + 300 + 6 + 16);
+		let chunk = assert_roundtrip(&bytes);
+		assert_eq!(chunk.header.size_t_size, size_t_size);
+		assert_eq!(chunk.main, main);
+	}
+}
+
+#[test]
+fn invalid_headers() {
+	let good = sample_bytes(Header::default());
+	let cases: &[(usize, &[u8], &str)] = &[
+		(1, b"X", "invalid signature"),
+		(4, &[0x54], "invalid version, expected 83 but got 84"),
+		(4, &[0x52], "Lua 5.2 bytecode is not supported"),
+		(5, &[1], "invalid format, expected 0 but got 1"),
+		(6, &[0x19, 0x93, b'\n', b'\n'], "invalid test data"),
+		(17, &[0x34, 0x12], "invalid test integer"),
+		(25, &[0, 0, 0, 0, 0, 0, 0xf0, 0x3f], "invalid test number, expected 370.5 but got 1.0"),
+	];
+	for &(offset, patch, message) in cases {
+		let mut bytes = good.clone();
+		bytes[offset..offset + patch.len()].copy_from_slice(patch);
+		match read_chunk(&bytes[..]) {
+			Err(ReadError::Invalid(ref msg)) => assert!(msg.starts_with(message), "{:?} for {:?}", msg, message),
+			other => panic!("unexpected result {:?} for {:?}", other, message),
+		}
+	}
+}
+
+#[test]
+fn preserve_string_encoding() {
+	let mut main = Function::empty_main();
+	main.constants = vec![Constant::ShortString("abc".into()), Constant::ShortString("d".into())];
+	let mut bytes = Chunk { header: Header::default(), main }.to_bytes().unwrap();
+	// Rewrite the first constant's length in the long form, as another
+	// tool might.
+	assert_eq!(&bytes[58..64], &[4, 4, b'a', b'b', b'c', 4]);
+	bytes = [&bytes[..59], &[0xff, 4, 0, 0, 0, 0, 0, 0, 0], &bytes[60..]].concat();
+
+	// By default the length is read, then written in the shortest form.
+	let chunk = read_chunk(&bytes[..]).unwrap();
+	assert_eq!(chunk.main.constants[0], Constant::ShortString("abc".into()));
+	assert_eq!(chunk.to_bytes().unwrap().len(), bytes.len() - 8);
+
+	let read_options = ReadOptions { preserve_encoding: true, ..ReadOptions::default() };
+	let chunk = read_chunk_with(&bytes[..], &read_options).unwrap();
+	let long_lengths: Vec<bool> = chunk.main.constants.iter().map(|c| match *c {
+		Constant::ShortString(ref s) => s.long_length(),
+		_ => panic!("unexpected constant {:?}", c),
+	}).collect();
+	assert_eq!(long_lengths, vec![true, false]);
+	assert_eq!(chunk.to_bytes().unwrap().len(), bytes.len() - 8);
+	let write_options = WriteOptions { preserve_encoding: true, ..WriteOptions::default() };
+	let mut written = Vec::new();
+	write_chunk_with(&mut written, &chunk, &write_options).unwrap();
+	assert_eq!(written, bytes);
+}
+
+#[test]
+fn compact_lineinfo() {
+	let mut lines = vec![2, 2, 3, 1, 200, 199, 72, 72];
+	lines.extend((0..300).map(|i| 80 + i / 7));
+	let compact = CompactLineInfo::encode(&lines, 1);
+	assert_eq!(&compact.lineinfo[..8], &[1, 0, 1, -2, ABSLINEINFO, -1, -127, 0]);
+	// Besides the jump to 200, an absolute line is recorded every 128
+	// instructions, the first 128 after the last.
+	let pcs: Vec<Int> = compact.abslineinfo.iter().map(|abs| abs.pc).collect();
+	assert_eq!(pcs, vec![4, 132, 260]);
+	assert_eq!(compact.abslineinfo[0], AbsLineInfo { pc: 4, line: 200 });
+
+	assert_eq!(compact.decode(1), lines);
+	for (pc, &line) in lines.iter().enumerate() {
+		assert_eq!(compact.line_at(pc, 1), Some(line));
+	}
+	assert_eq!(compact.line_at(lines.len(), 1), None);
+
+	let mut function = Function::empty_main();
+	function.line_start = 1;
+	function.debug.lineinfo = lines;
+	assert_eq!(function.compact_lineinfo(), compact);
+}
+
+#[test]
+fn constant_type_tags() {
+	let constants = [
+		Constant::Nil,
+		Constant::Boolean(true),
+		Constant::Float(0.5),
+		Constant::Int(1),
+		Constant::ShortString("a".into()),
+		Constant::LongString("b".into()),
+	];
+	let lua53: Vec<Option<u8>> = constants.iter().map(|c| c.type_tag(Version::Lua53)).collect();
+	assert_eq!(lua53, vec![Some(0), Some(1), Some(3), Some(0x13), Some(4), Some(0x14)]);
+	let lua51: Vec<Option<u8>> = constants.iter().map(|c| c.type_tag(Version::Lua51)).collect();
+	assert_eq!(lua51, vec![Some(0), Some(1), Some(3), None, Some(4), Some(4)]);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_roundtrip() {
+	let mut main = sample();
+	main.constants.extend(vec![
+		Constant::Float(f64::NAN),
+		Constant::Float(f64::INFINITY),
+		Constant::Float(-0.0),
+		Constant::RawFloat(vec![1, 2, 3]),
+		Constant::ShortString(LuaString::new(&b"\xff\x00"[..])),
+	]);
+	let chunk = Chunk { header: Header { endian: Endian::Big, ..Header::default() }, main };
+	let json = to_json(&chunk).unwrap();
+	assert!(json.starts_with(r#"{"lua_kit_schema":2,"chunk":{"header":{"version":"Lua53","#));
+	assert!(json.contains(r#"{"ShortString":[255,0]}"#));
+	assert!(json.contains(r#"{"Float":"0x7ff0000000000000"}"#));
+	assert_eq!(from_json(&json).unwrap(), chunk);
+
+	let old = json.replace(r#""lua_kit_schema":2"#, r#""lua_kit_schema":1"#);
+	match from_json(&old) {
+		Err(JsonError::Schema(1)) => {}
+		other => panic!("unexpected result {:?}", other),
+	}
+	assert!(from_json("{}").is_err());
+}
+
+#[test]
+fn roundtrip_embedded_nuls() {
+	let mut main = Function::empty_main();
+	main.source = Some("=a\0b".into());
+	main.constants = vec![
+		Constant::ShortString("a\0b".into()),
+		Constant::ShortString("\0".into()),
+		Constant::LongString([&b"\0"[..], &[b'c'; 300][..], &b"\0"[..]].concat().into()),
+	];
+	let bytes = Chunk { header: Header::default(), main: main.clone() }.to_bytes().unwrap();
+	// The source and the first constant are each their length plus one
+	// and then their bytes, NUL and all.
+	assert_eq!(&bytes[34..39], &[5, b'=', b'a', 0, b'b']);
+	assert_eq!(&bytes[62..67], &[4, 4, b'a', 0, b'b']);
+	assert_eq!(assert_roundtrip(&bytes).main, main);
+
+	let text = main.disassemble();
+	assert!(text.contains(r#"string "a\000b""#));
+	assert_eq!(assemble(&text).unwrap(), main);
+}
+
+#[test]
+fn check_limits() {
+	let header = Header::default();
+	let mut function = sample();
+	assert_eq!(function.check_limits(&header), vec![]);
+	function.upvalues = vec![Upvalue::Stack(0); 256];
+	function.constants = vec![Constant::Nil; 257];
+	assert_eq!(function.check_limits(&Header { int_size: 8, ..header }), vec![
+		LimitError { limit: Limit::Constants, max: 256, actual: 257 },
+		LimitError { limit: Limit::Upvalues, max: 255, actual: 256 },
+	]);
+}
+
+#[test]
+fn roundtrip_nested_protos_with_debug() {
+	let mut main = sample();
+	let mut second = sample().protos[0].clone();
+	second.line_start = 5;
+	second.line_end = 9;
+	second.debug.lineinfo = vec![9];
+	second.debug.localvars.push(LocalVar { name: "b".into(), start_pc: 0, end_pc: 1 });
+	second.protos.push(sample().protos[0].clone());
+	second.code.insert(0, encode_bx(Opcode::Closure, 0, 0));
+	second.debug.lineinfo.insert(0, 6);
+	main.protos.push(second);
+	main.code.insert(1, encode_bx(Opcode::Closure, 1, 1));
+	main.debug.lineinfo.insert(1, 5);
+	main.debug.localvars = vec![LocalVar { name: "f".into(), start_pc: 1, end_pc: 3 }];
+
+	let bytes = Chunk { header: Header::default(), main: main.clone() }.to_bytes().unwrap();
+	let (chunk, index) = read_chunk_indexed(&bytes[..]).unwrap();
+	assert_eq!(chunk.main, main);
+	assert_eq!(assert_roundtrip(&bytes), chunk);
+
+	// Each function's nested functions lie between its upvalues and its
+	// debug info, so the main function's line info count directly follows
+	// the last nested function.
+	let (_, last_end) = index[&vec![1]];
+	assert_eq!(&bytes[last_end..last_end + 4], &[3, 0, 0, 0]);
+	let (nested_start, nested_end) = index[&vec![1, 0]];
+	assert!(index[&vec![1]].0 < nested_start && nested_end < last_end);
+	assert_eq!(&bytes[nested_end..nested_end + 8], &[2, 0, 0, 0, 6, 0, 0, 0]);
+
+	// Options apply, and offsets count any bytes skipped before the chunk.
+	let options = ReadOptions { max_depth: 1, ..ReadOptions::default() };
+	match read_chunk_indexed_with(&bytes[..], &options) {
+		Err(ReadError::TooDeep(1)) => {}
+		other => panic!("unexpected result {:?}", other),
+	}
+	let mut prefixed = b"#!lua\n".to_vec();
+	prefixed.extend_from_slice(&bytes);
+	let options = ReadOptions { skip_to_signature: true, ..ReadOptions::default() };
+	let (skipped, skipped_index) = read_chunk_indexed_with(&prefixed[..], &options).unwrap();
+	assert_eq!(skipped, chunk);
+	assert_eq!(skipped_index[&vec![1, 0]], (nested_start + 6, nested_end + 6));
+}
+
+#[test]
+fn sbx_range() {
+	use bytecode::{self, DecodedInstruction, SbxOutOfRange, MIN_SBX, MAX_SBX};
+	for &sbx in &[MIN_SBX, -1, 0, 1, MAX_SBX] {
+		let ins = bytecode::try_encode_sbx(Opcode::Jump, 3, sbx).unwrap();
+		assert_eq!(bytecode::decode(ins), Some(DecodedInstruction::AsBx(Opcode::Jump, 3, sbx)));
+	}
+	assert_eq!(bytecode::try_encode_sbx(Opcode::Jump, 0, MIN_SBX - 1), Err(SbxOutOfRange(MIN_SBX - 1)));
+	assert_eq!(bytecode::try_encode_sbx(Opcode::Jump, 0, MAX_SBX + 1), Err(SbxOutOfRange(MAX_SBX + 1)));
+}
+
+/// `local x = 1 local function f() return x end` as compiled by `luac` 5.1
+/// for a big-endian 32-bit platform, such as the PowerPC of some consoles.
+const LUA51_BE: &'static [u8] = &[
+	// header: version, format, byte order, sizes, and integral flag
+	0x1b, b'L', b'u', b'a', 0x51, 0, 0, 4, 4, 4, 8, 0,
+	// main: source, lines, nups, params, is_vararg, max stack size
+	0, 0, 0, 7, b'@', b't', b'.', b'l', b'u', b'a', 0,
+	0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 2,
+	// code: LOADK 0 -1, CLOSURE 1 0, MOVE 0 0, RETURN 0 1
+	0, 0, 0, 4, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x64,
+	0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x1e,
+	// constants: 1
+	0, 0, 0, 1, 3, 0x3f, 0xf0, 0, 0, 0, 0, 0, 0,
+	// f: no source, lines, one upvalue, params, is_vararg, max stack size
+	0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 2, 1, 0, 0, 2,
+	// code: GETUPVAL 0 0, RETURN 0 2, RETURN 0 1
+	0, 0, 0, 3, 0x00, 0x00, 0x00, 0x04, 0x01, 0x00, 0x00, 0x1e, 0x00, 0x80, 0x00, 0x1e,
+	// no constants or functions, then line info, no locals, and upvalue x
+	0, 0, 0, 0, 0, 0, 0, 0,
+	0, 0, 0, 3, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 2,
+	0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2, b'x', 0,
+	// main: line info, locals x and f, and no upvalue names
+	0, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 2,
+	0, 0, 0, 2, 0, 0, 0, 2, b'x', 0, 0, 0, 0, 1, 0, 0, 0, 3,
+	0, 0, 0, 2, b'f', 0, 0, 0, 0, 3, 0, 0, 0, 3,
+	0, 0, 0, 0,
+];
+
+#[test]
+fn read_lua51_big_endian() {
+	let (chunk, len) = read_chunk_counted(LUA51_BE).unwrap();
+	assert_eq!(len, LUA51_BE.len());
+	assert_eq!(chunk.header, Header {
+		version: Version::Lua51,
+		endian: Endian::Big,
+		int_size: 4,
+		size_t_size: 4,
+		instruction_size: 4,
+		integer_size: 8,
+		number_size: 8,
+	});
+	let main = chunk.main;
+	assert_eq!(main.source, Some("@t.lua".into()));
+	assert!(main.has_vararg() && !main.has_arg(Version::Lua51));
+	assert_eq!(main.code, vec![0x01, 0x64, 0x00, 0x80001e]);
+	assert_eq!(main.constants, vec![Constant::Float(1.0)]);
+	assert_eq!(main.upvalues, vec![]);
+	assert_eq!(main.debug.lineinfo, vec![1, 2, 2, 2]);
+	assert_eq!(main.debug.localvars, vec![
+		LocalVar { name: "x".into(), start_pc: 1, end_pc: 3 },
+		LocalVar { name: "f".into(), start_pc: 3, end_pc: 3 },
+	]);
+
+	// The upvalue's descriptor comes from the MOVE following the CLOSURE.
+	let f = &main.protos[0];
+	assert_eq!(f.source, None);
+	assert_eq!((f.line_start, f.line_end, f.max_stack_size), (2, 2, 2));
+	assert_eq!(f.upvalues, vec![Upvalue::Stack(0)]);
+	assert_eq!(f.debug.upvalues, vec![LuaString::from("x")]);
+
+	assert_eq!(read_constants_only(LUA51_BE).unwrap(), vec![Constant::Float(1.0)]);
+
+	let mut integral = LUA51_BE.to_vec();
+	integral[11] = 1;
+	assert!(read_chunk(&integral[..]).is_err());
+}
+
+#[test]
+fn roundtrip_lua51_big_endian() {
+	let chunk = assert_roundtrip(LUA51_BE);
+
+	// Only the byte order field and the multi-byte values differ in the
+	// other byte order; there are no test values.
+	let little = Chunk { header: Header { endian: Endian::Little, ..chunk.header }, ..chunk.clone() };
+	let bytes = little.to_bytes().unwrap();
+	assert_eq!(bytes.len(), LUA51_BE.len());
+	assert_eq!(&bytes[..16], &[0x1b, b'L', b'u', b'a', 0x51, 0, 1, 4, 4, 4, 8, 0, 7, 0, 0, 0]);
+	assert_eq!(assert_roundtrip(&bytes), little);
+
+	// Lua 5.1 has a single tag for strings, and no integers.
+	let mut main = chunk.main.clone();
+	main.constants.push(Constant::LongString("y".into()));
+	let bytes = Chunk { header: chunk.header, main }.to_bytes().unwrap();
+	let reread = read_chunk(&bytes[..]).unwrap();
+	assert_eq!(reread.main.constants.last(), Some(&Constant::ShortString("y".into())));
+	let mut main = chunk.main.clone();
+	main.constants.push(Constant::Int(1));
+	match (Chunk { header: chunk.header, main }).to_bytes() {
+		Err(WriteError::IntegerConstant(1)) => {}
+		other => panic!("unexpected result {:?}", other),
+	}
+
+	let lua52 = Header { version: Version::Lua52, ..Header::default() };
+	match lua52.check_writable() {
+		Err(WriteError::UnsupportedVersion(Version::Lua52)) => {}
+		other => panic!("unexpected result {:?}", other),
+	}
+}
+
+#[test]
+fn max_depth() {
+	let mut main = Function::empty_main();
+	for _ in 0..3 {
+		let mut outer = Function::empty_main();
+		outer.protos.push(main);
+		main = outer;
+	}
+	let chunk = Chunk { header: Header::default(), main };
+
+	let write_options = WriteOptions { max_depth: 2, ..WriteOptions::default() };
+	match write_chunk_with(Vec::new(), &chunk, &write_options) {
+		Err(WriteError::TooDeep(2)) => {}
+		other => panic!("unexpected result {:?}", other),
+	}
+	let bytes = chunk.to_bytes().unwrap();
+	let read_options = ReadOptions { max_depth: 2, ..ReadOptions::default() };
+	match read_chunk_with(&bytes[..], &read_options) {
+		Err(ReadError::TooDeep(2)) => {}
+		other => panic!("unexpected result {:?}", other),
+	}
+	let read_options = ReadOptions { max_depth: 3, ..ReadOptions::default() };
+	assert_eq!(read_chunk_with(&bytes[..], &read_options).unwrap(), chunk);
+}
+
+#[test]
+fn canonicalize_unconvertible() {
+	let lua51 = Header { version: Version::Lua51, ..Header::default() };
+	let mut chunk = Chunk { header: lua51, main: Function::empty_main() };
+	assert_eq!(chunk.canonicalize(), Err(EditError::UnsupportedVersion(Version::Lua51)));
+	assert_eq!(chunk.header, lua51);
+
+	let header = Header::default().with_number_size(4);
+	let mut main = sample();
+	main.protos[0].constants.push(Constant::RawFloat(vec![0; 4]));
+	let mut chunk = Chunk { header, main };
+	assert_eq!(chunk.canonicalize(), Err(EditError::RawFloat));
+	assert_eq!(chunk.header, header);
+	assert_eq!(chunk.main.protos[0].constants, [Constant::RawFloat(vec![0; 4])]);
+}
+
+#[test]
+fn source_files() {
+	let mut main = sample();
+	main.protos[0].source = Some(LuaString::new(&b"=\xffname"[..]));
+	let mut literal = main.protos[0].clone();
+	literal.source = Some("return 1".into());
+	main.protos.push(literal);
+	let mut empty = main.protos[0].clone();
+	empty.source = Some("@".into());
+	main.protos.push(empty);
+	let files: Vec<_> = Chunk::new(main).source_files().into_iter().collect();
+	assert_eq!(files, [b"test.lua".to_vec(), b"\xffname".to_vec()]);
+}
+
+#[test]
+fn proto_at_path() {
+	let mut chunk = Chunk::new(sample());
+	let paths = chunk.find_constant(&Constant::Int(5));
+	assert_eq!(paths, [Vec::<usize>::new()]);
+	assert_eq!(chunk.proto_at(&paths[0]), Some(&chunk.main));
+	assert_eq!(chunk.proto_at(&[0]), Some(&chunk.main.protos[0]));
+	assert_eq!(chunk.proto_at(&[0, 0]), None);
+	assert_eq!(chunk.proto_at(&[1]), None);
+	chunk.proto_at_mut(&[0]).unwrap().num_params = 2;
+	assert_eq!(chunk.main.protos[0].num_params, 2);
+	assert!(chunk.proto_at_mut(&[2]).is_none());
+}
+
+#[test]
+fn chunk_opcode_histogram() {
+	let mut chunk = Chunk::new(sample());
+	assert_eq!(chunk.opcode_histogram_sorted(), [(Opcode::Return, 2), (Opcode::Closure, 1)]);
+	assert_eq!(chunk.opcode_histogram(), chunk.main.opcode_histogram());
+	chunk.header.version = Version::Lua51;
+	assert!(chunk.opcode_histogram().is_empty());
+	assert!(chunk.opcode_histogram_sorted().is_empty());
+}
+
+#[test]
+fn read_truncated_slice() {
+	let bytes = sample_bytes(Header::default());
+	match read_chunk_from_slice(&[]) {
+		Err(ReadError::Eof) => {}
+		other => panic!("unexpected result {:?}", other),
+	}
+	for len in 1..bytes.len() {
+		match read_chunk_from_slice(&bytes[..len]) {
+			Err(ReadError::Truncated) => {}
+			other => panic!("unexpected result {:?} reading {} bytes", other, len),
+		}
+	}
+	assert_eq!(read_chunk_from_slice(&bytes).unwrap().main, sample());
+}
+
+#[test]
+fn read_file_io_errors() {
+	let bytes = sample_bytes(Header::default());
+	assert_eq!(read_file(&bytes[..]).unwrap(), sample());
+	let err = read_file(&bytes[..bytes.len() - 1]).unwrap_err();
+	assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+	let err = read_file(&b"\x1bLuaQ"[..]).unwrap_err();
+	assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+	let err = read_file(&b"\x1bLJ\x02\x00"[..]).unwrap_err();
+	assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
@@ -0,0 +1,234 @@
+//! Queries over trees of nested functions.
+
+use std::collections::{BTreeSet, HashMap};
+
+use bytecode::Opcode;
+use super::{Chunk, Constant, Function, Version};
+
+/// The location of a nested function, as a sequence of indices into `protos`.
+///
+/// The empty path refers to the outermost function.
+pub type ProtoPath = Vec<usize>;
+
+/// Totals over every function in a chunk.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ChunkStats {
+	/// The number of functions, including the main function.
+	pub functions: usize,
+	/// The number of instructions.
+	pub instructions: usize,
+	/// The number of constants, counting each function's separately.
+	pub constants: usize,
+}
+
+/// An iterator over a function and all its nested functions, depth-first.
+pub struct IterProtos<'a> {
+	stack: Vec<(ProtoPath, &'a Function)>,
+}
+
+impl<'a> Iterator for IterProtos<'a> {
+	type Item = (ProtoPath, &'a Function);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.stack.pop().map(|(path, function)| {
+			for (i, proto) in function.protos.iter().enumerate().rev() {
+				let mut child = path.clone();
+				child.push(i);
+				self.stack.push((child, proto));
+			}
+			(path, function)
+		})
+	}
+}
+
+impl Function {
+	/// Iterate over this function and all nested functions, depth-first
+	/// with parents before children, along with their paths.
+	pub fn iter_protos(&self) -> IterProtos<'_> {
+		IterProtos { stack: vec![(Vec::new(), self)] }
+	}
+
+	/// The number of instructions in this function, excluding nested
+	/// functions.
+	pub fn instruction_count(&self) -> usize {
+		self.code.len()
+	}
+
+	/// The number of instructions in this function and all nested
+	/// functions.
+	pub fn total_instruction_count(&self) -> usize {
+		self.iter_protos().map(|(_, function)| function.instruction_count()).sum()
+	}
+
+	/// Get the nested function at the given path.
+	///
+	/// Returns `None` if any index along the path is out of range.
+	pub fn proto_at(&self, path: &[usize]) -> Option<&Function> {
+		let mut function = self;
+		for &i in path {
+			function = function.protos.get(i)?;
+		}
+		Some(function)
+	}
+
+	/// Mutably get the nested function at the given path.
+	///
+	/// Returns `None` if any index along the path is out of range.
+	pub fn proto_at_mut(&mut self, path: &[usize]) -> Option<&mut Function> {
+		let mut function = self;
+		for &i in path {
+			function = function.protos.get_mut(i)?;
+		}
+		Some(function)
+	}
+
+	/// Find every function whose constant table contains `needle`.
+	///
+	/// Paths are returned in depth-first order, parents before children.
+	pub fn find_constant(&self, needle: &Constant) -> Vec<ProtoPath> {
+		let mut paths = Vec::new();
+		find_constant(self, needle, &mut Vec::new(), &mut paths);
+		paths
+	}
+
+	/// Count the occurrences of each opcode in this function and all nested
+	/// functions.
+	///
+	/// Instructions with invalid opcodes are not counted.
+	pub fn opcode_histogram(&self) -> HashMap<Opcode, usize> {
+		let mut histogram = HashMap::new();
+		for (_, function) in self.iter_protos() {
+			for (_, ins) in function.decoded_instructions() {
+				if let Some(ins) = ins {
+					*histogram.entry(ins.opcode()).or_insert(0) += 1;
+				}
+			}
+		}
+		histogram
+	}
+
+	/// Count opcodes as `opcode_histogram`, ordered from most to least
+	/// frequent and then by opcode.
+	pub fn opcode_histogram_sorted(&self) -> Vec<(Opcode, usize)> {
+		let mut counts: Vec<_> = self.opcode_histogram().into_iter().collect();
+		counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+		counts
+	}
+
+	/// List each distinct constant along with every function using it.
+	///
+	/// Constants are listed in the order they are first seen, searching
+	/// depth-first. Constants are considered equal if they have the same
+	/// `Constant::dedup_key`.
+	pub fn shared_constants(&self) -> Vec<(Constant, Vec<ProtoPath>)> {
+		let mut shared: Vec<(Constant, Vec<ProtoPath>)> = Vec::new();
+		let mut index = HashMap::new();
+		for (path, function) in self.iter_protos() {
+			for constant in &function.constants {
+				let i = *index.entry(constant.dedup_key()).or_insert_with(|| {
+					shared.push((constant.clone(), Vec::new()));
+					shared.len() - 1
+				});
+				if shared[i].1.last() != Some(&path) {
+					shared[i].1.push(path.clone());
+				}
+			}
+		}
+		shared
+	}
+}
+
+impl Chunk {
+	/// Get the function at the given path, starting from the main function.
+	///
+	/// Returns `None` if any index along the path is out of range.
+	pub fn proto_at(&self, path: &[usize]) -> Option<&Function> {
+		self.main.proto_at(path)
+	}
+
+	/// Mutably get the function at the given path, starting from the main
+	/// function.
+	///
+	/// Returns `None` if any index along the path is out of range.
+	pub fn proto_at_mut(&mut self, path: &[usize]) -> Option<&mut Function> {
+		self.main.proto_at_mut(path)
+	}
+
+	/// Find every function in the chunk whose constant table contains
+	/// `needle`, as `Function::find_constant` does for the main function.
+	pub fn find_constant(&self, needle: &Constant) -> Vec<ProtoPath> {
+		self.main.find_constant(needle)
+	}
+
+	/// Count the occurrences of each opcode in every function in the chunk.
+	///
+	/// Only Lua 5.3 opcodes are decoded, so the histogram is empty for a
+	/// chunk whose header is for another version.
+	pub fn opcode_histogram(&self) -> HashMap<Opcode, usize> {
+		if self.header.version != Version::Lua53 {
+			return HashMap::new();
+		}
+		self.main.opcode_histogram()
+	}
+
+	/// Count opcodes as `opcode_histogram`, ordered from most to least
+	/// frequent and then by opcode.
+	pub fn opcode_histogram_sorted(&self) -> Vec<(Opcode, usize)> {
+		if self.header.version != Version::Lua53 {
+			return Vec::new();
+		}
+		self.main.opcode_histogram_sorted()
+	}
+
+	/// List each distinct constant in the chunk along with every function
+	/// using it, as `Function::shared_constants` does for the main function.
+	pub fn shared_constants(&self) -> Vec<(Constant, Vec<ProtoPath>)> {
+		self.main.shared_constants()
+	}
+
+	/// Count the functions, instructions, and constants in the chunk.
+	pub fn stats(&self) -> ChunkStats {
+		let mut stats = ChunkStats::default();
+		for (_, function) in self.main.iter_protos() {
+			stats.functions += 1;
+			stats.instructions += function.instruction_count();
+			stats.constants += function.constants.len();
+		}
+		stats
+	}
+
+	/// The deepest nesting level of any function in the chunk.
+	///
+	/// The main function is at depth 0 and each function nested within
+	/// another is one deeper than it. The tree is walked without
+	/// recursion, so this is safe to call on arbitrarily deep chunks.
+	pub fn max_depth(&self) -> usize {
+		self.main.iter_protos().map(|(path, _)| path.len()).max().unwrap_or(0)
+	}
+
+	/// The distinct names recorded as the source of any function in the
+	/// chunk.
+	///
+	/// Sources using the `@file` and `=name` conventions are included as
+	/// their raw bytes, without the leading `@` or `=`. Empty names and
+	/// sources holding the source text itself are skipped.
+	pub fn source_files(&self) -> BTreeSet<Vec<u8>> {
+		self.main.iter_protos().filter_map(|(_, function)| {
+			let source = function.source.as_ref()?;
+			source.strip_prefix(b"@").or_else(|| source.strip_prefix(b"="))
+				.filter(|name| !name.is_empty())
+				.map(|name| name.to_vec())
+		}).collect()
+	}
+}
+
+fn find_constant(function: &Function, needle: &Constant, path: &mut ProtoPath, paths: &mut Vec<ProtoPath>) {
+	if function.constants.iter().any(|c| c == needle) {
+		paths.push(path.clone());
+	}
+	for (i, proto) in function.protos.iter().enumerate() {
+		path.push(i);
+		find_constant(proto, needle, path, paths);
+		path.pop();
+	}
+}
@@ -0,0 +1,292 @@
+//! Checks for functions which cannot be represented correctly.
+
+use std::{cmp, fmt};
+
+use analysis::{constant_refs, falls_through};
+use bytecode::{self, Opcode, MAXARG_BX, MAXINDEXRK};
+use super::{Chunk, Constant, Function, Header, Int, ProtoPath, Version};
+
+/// A limit of the bytecode format.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Limit {
+	/// The number of instructions, beyond which jumps cannot reach.
+	Code,
+	/// The number of constants, beyond which `RK` parameters cannot refer
+	/// to them.
+	Constants,
+	/// The length of a list, beyond which its count can't be written as an
+	/// `int`.
+	Count,
+	/// The number of upvalues, which every version records in a byte.
+	Upvalues,
+}
+
+/// A limit of the bytecode format which a function exceeds.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LimitError {
+	/// The limit which was exceeded.
+	pub limit: Limit,
+	/// The largest permitted count.
+	pub max: usize,
+	/// The function's actual count.
+	pub actual: usize,
+}
+
+impl LimitError {
+	/// How far the limit was exceeded by.
+	pub fn excess(&self) -> usize {
+		self.actual - self.max
+	}
+}
+
+impl fmt::Display for LimitError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let what = match self.limit {
+			Limit::Code => "instructions",
+			Limit::Constants => "constants",
+			Limit::Count => "list entries",
+			Limit::Upvalues => "upvalues",
+		};
+		write!(f, "{} {} exceeds the limit of {} by {}", self.actual, what, self.max, self.excess())
+	}
+}
+
+/// A function whose debug information names some, but not all, of its
+/// upvalues.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UpvalueNamesError {
+	/// The number of upvalue names.
+	pub names: usize,
+	/// The number of upvalues.
+	pub upvalues: usize,
+}
+
+impl fmt::Display for UpvalueNamesError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} upvalue names for {} upvalues", self.names, self.upvalues)
+	}
+}
+
+/// A way control can run past the end of a function's code.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TerminationError {
+	/// The function has no code.
+	Empty,
+	/// The last instruction may continue to the instruction after it.
+	FallsThrough {
+		/// The program counter of the last instruction.
+		pc: usize,
+	},
+	/// A branch targets the end of the code or beyond.
+	BranchPastEnd {
+		/// The program counter of the branch.
+		from: usize,
+		/// The program counter it targets.
+		to: usize,
+	},
+}
+
+impl fmt::Display for TerminationError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			TerminationError::Empty => write!(f, "function has no code"),
+			TerminationError::FallsThrough { pc } => write!(f,
+				"last instruction at {} may fall off the end of the code", pc),
+			TerminationError::BranchPastEnd { from, to } => write!(f,
+				"branch at {} targets {}, past the end of the code", from, to),
+		}
+	}
+}
+
+/// A reason a chunk cannot be loaded by a version of Lua.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CompatError {
+	/// A function uses an opcode the version doesn't have.
+	UnsupportedOpcode {
+		/// The location of the function.
+		path: ProtoPath,
+		/// The program counter of the instruction.
+		pc: usize,
+		/// The opcode of the instruction.
+		opcode: Opcode,
+	},
+	/// A function has an integer constant, but the version has no integers.
+	IntegerConstant {
+		/// The location of the function.
+		path: ProtoPath,
+		/// The index of the constant.
+		index: usize,
+	},
+	/// The chunk is in another version's format.
+	Format {
+		/// The version of the chunk's format.
+		chunk: Version,
+		/// The version it was checked against.
+		target: Version,
+	},
+}
+
+impl fmt::Display for CompatError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			CompatError::UnsupportedOpcode { ref path, pc, opcode } => write!(f,
+				"function {:?} uses {} at {}, which the target version lacks", path, opcode.name(), pc),
+			CompatError::IntegerConstant { ref path, index } => write!(f,
+				"function {:?} has integer constant {}, but the target version has no integers", path, index),
+			CompatError::Format { chunk, target } => write!(f,
+				"chunk is in the {:?} format, not {:?}", chunk, target),
+		}
+	}
+}
+
+impl Chunk {
+	/// Check whether the given version of Lua could load the chunk.
+	///
+	/// Every function's opcodes and constants are checked first, so that
+	/// the first error describes a problem in the chunk's content if there
+	/// is one; the format itself is checked last.
+	///
+	/// Only Lua 5.3 code can be decoded, so a chunk in another version's
+	/// format is only checked to be in the given version's format.
+	pub fn is_compatible_with(&self, version: Version) -> Result<(), CompatError> {
+		let format = self.header.version;
+		if format != Version::Lua53 {
+			if format != version {
+				return Err(CompatError::Format { chunk: format, target: version });
+			}
+			return Ok(());
+		}
+		for (path, function) in self.main.iter_protos() {
+			if let Some(&(pc, opcode)) = function.unsupported_opcodes_for(version).first() {
+				return Err(CompatError::UnsupportedOpcode { path, pc, opcode });
+			}
+			if !version.has_integers() {
+				let int = function.constants.iter().position(|c| matches!(*c, Constant::Int(_)));
+				if let Some(index) = int {
+					return Err(CompatError::IntegerConstant { path, index });
+				}
+			}
+		}
+		if version != Version::Lua53 {
+			return Err(CompatError::Format { chunk: Version::Lua53, target: version });
+		}
+		Ok(())
+	}
+}
+
+impl Function {
+	/// Check the function against the limits of the instruction encoding.
+	///
+	/// Nested functions are not checked. `max_stack_size` needs no check, as
+	/// its type already restricts it to the 255 addressable registers.
+	///
+	/// Every list's length is written as an `int` of the header's
+	/// `int_size`. The code and constants are limited far more tightly by
+	/// the instruction encoding, and the other lists are checked against the
+	/// largest `int` of that size, or of `Int` if that is smaller. The number
+	/// of upvalues is limited to 255 by the byte Lua uses to count them.
+	pub fn check_limits(&self, header: &Header) -> Vec<LimitError> {
+		let max_count = match header.int_size {
+			4 => i32::MAX as u64,
+			_ => i64::MAX as u64,
+		};
+		let max_count = cmp::min(max_count, Int::MAX as u64) as usize;
+		let mut errors = Vec::new();
+		let mut check = |limit, max, actual| if actual > max {
+			errors.push(LimitError { limit, max, actual });
+		};
+		check(Limit::Code, MAXARG_BX as usize, self.code.len());
+		check(Limit::Constants, MAXINDEXRK as usize + 1, self.constants.len());
+		check(Limit::Upvalues, u8::MAX as usize, self.upvalues.len());
+		for &len in &[
+			self.protos.len(),
+			self.debug.lineinfo.len(),
+			self.debug.localvars.len(),
+			self.debug.upvalues.len(),
+		] {
+			check(Limit::Count, max_count, len);
+		}
+		errors
+	}
+
+	/// The number of upvalues to record for the function.
+	///
+	/// Lua 5.1 records the count as `nups` in each function's header and
+	/// describes each upvalue with a pseudo-instruction after `CLOSURE`,
+	/// while later versions write a descriptor per upvalue. Either way, the
+	/// count is the number of entries in `upvalues`, and must fit in the
+	/// byte every version records it in.
+	pub fn upvalue_count(&self) -> usize {
+		self.upvalues.len()
+	}
+
+	/// Check that control cannot run past the end of the function's code.
+	///
+	/// The last instruction must be one which never continues to the next,
+	/// such as `Return`, and no branch may target the end of the code.
+	/// Undecodable instructions are assumed to fall through. Nested
+	/// functions are not checked.
+	pub fn check_terminated(&self) -> Result<(), TerminationError> {
+		let last = match self.code.len() {
+			0 => return Err(TerminationError::Empty),
+			len => len - 1,
+		};
+		match bytecode::decode(self.code[last]) {
+			Some(ref ins) if !falls_through(ins) => {}
+			_ => return Err(TerminationError::FallsThrough { pc: last }),
+		}
+		match self.jump_targets().into_iter().find(|&(_, to)| to > last) {
+			Some((from, to)) => Err(TerminationError::BranchPastEnd { from, to }),
+			None => Ok(()),
+		}
+	}
+
+	/// Check that the function's upvalue names agree with its upvalues.
+	///
+	/// Lua writes either a name for every upvalue or, when stripping, no
+	/// names at all, so any other count means the names are attached to
+	/// the wrong upvalues. Nested functions are not checked.
+	pub fn check_upvalue_names(&self) -> Result<(), UpvalueNamesError> {
+		let (names, upvalues) = (self.debug.upvalues.len(), self.upvalue_count());
+		if names == 0 || names == upvalues {
+			Ok(())
+		} else {
+			Err(UpvalueNamesError { names, upvalues })
+		}
+	}
+
+	/// Whether the function's upvalue names agree with its upvalues, as
+	/// checked by `check_upvalue_names`.
+	pub fn upvalue_names_match(&self) -> bool {
+		self.check_upvalue_names().is_ok()
+	}
+
+	/// List the constant references in the function's code which are past
+	/// the end of its constant table, as `(pc, index)`.
+	///
+	/// Nested functions are not checked.
+	pub fn check_constant_refs(&self) -> Vec<(usize, u32)> {
+		let mut bad = Vec::new();
+		for (pc, ins) in self.decoded_instructions() {
+			if let Some(ins) = ins {
+				for k in constant_refs(&ins, self.code.get(pc + 1).cloned()) {
+					if k >= self.constants.len() {
+						bad.push((pc, k as u32));
+					}
+				}
+			}
+		}
+		bad
+	}
+
+	/// List the instructions whose opcodes have no equivalent in the given
+	/// version of Lua, with their program counters.
+	///
+	/// Nested functions are not checked, and invalid opcodes are ignored.
+	pub fn unsupported_opcodes_for(&self, target: Version) -> Vec<(usize, Opcode)> {
+		self.decoded_instructions().filter_map(|(pc, ins)| match ins {
+			Some(ins) if !ins.opcode().exists_in(target) => Some((pc, ins.opcode())),
+			_ => None,
+		}).collect()
+	}
+}
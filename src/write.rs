@@ -1,115 +1,763 @@
 //! Serialization code.
 
-use std::io::{self, Write};
-use std::mem::size_of;
-use byteorder::WriteBytesExt;
-use byteorder::NativeEndian as E;
+use std::{error, fmt, mem};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::marker::PhantomData;
+use byteorder::{self, WriteBytesExt, ByteOrder, LittleEndian, BigEndian};
 
 use super::{
 	SIGNATURE, FORMAT, VERSION, DATA, TEST_INT, TEST_NUMBER,
-	Int, Size, Instruction, Integer, Number,
-	Constant, Upvalue, Function,
+	Int, Instruction, Integer, Number,
+	Endian, Header, Chunk, Constant, Upvalue, Function, LuaString, Version,
 };
 
-/// Serialize a `Function` to bytecode.
-pub fn write_file<W: Write>(write: W, function: &Function) -> io::Result<()> {
-	let mut writer = Writer { out: write };
-	try!(writer.write_header());
-	try!(writer.out.write_u8(function.upvalues.len() as u8));
-	writer.write_function(function)
+/// An error encountered while serializing bytecode.
+#[derive(Debug)]
+pub enum WriteError {
+	/// The underlying writer failed.
+	Io(io::Error),
+	/// A string was longer than the format can represent.
+	StringTooLong(usize),
+	/// A value could not be represented in the width the format gives it.
+	ValueDoesNotFit {
+		/// A description of the value.
+		field: &'static str,
+		/// The value which did not fit.
+		value: u64,
+	},
+	/// The header specified a size which is not supported.
+	UnsupportedSize {
+		/// The name of the size.
+		field: &'static str,
+		/// The size which was specified.
+		size: u8,
+	},
+	/// The output buffer was too short to hold the bytecode.
+	BufferTooSmall {
+		/// The length of the bytecode.
+		needed: usize,
+		/// The length of the buffer.
+		available: usize,
+	},
+	/// Functions were nested more deeply than `WriteOptions::max_depth`.
+	TooDeep(usize),
+	/// The header specified a version of Lua whose format can't be written.
+	UnsupportedVersion(Version),
+	/// An integer constant was written in the format of a version without
+	/// integers.
+	IntegerConstant(Integer),
 }
 
-struct Writer<W: Write> {
+impl fmt::Display for WriteError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			WriteError::Io(ref err) => err.fmt(f),
+			WriteError::StringTooLong(len) => write!(f, "string of length {} is too long", len),
+			WriteError::ValueDoesNotFit { field, value } => write!(f, "{} of {} does not fit", field, value),
+			WriteError::UnsupportedSize { field, size } => write!(f, "unsupported {} of {}", field, size),
+			WriteError::BufferTooSmall { needed, available } => write!(f,
+				"buffer too small, needed {} bytes but have {}", needed, available),
+			WriteError::TooDeep(max) => write!(f, "functions nested more than {} deep", max),
+			WriteError::UnsupportedVersion(version) => write!(f,
+				"writing Lua {}.{} bytecode is not supported", version.number() >> 4, version.number() & 0xf),
+			WriteError::IntegerConstant(value) => write!(f,
+				"integer constant {} in a version without integers", value),
+		}
+	}
+}
+
+impl error::Error for WriteError {
+	fn description(&self) -> &str {
+		match *self {
+			WriteError::Io(..) => "I/O error",
+			WriteError::StringTooLong(..) => "string too long",
+			WriteError::ValueDoesNotFit { .. } => "value does not fit",
+			WriteError::UnsupportedSize { .. } => "unsupported size",
+			WriteError::BufferTooSmall { .. } => "buffer too small",
+			WriteError::TooDeep(..) => "functions nested too deeply",
+			WriteError::UnsupportedVersion(..) => "unsupported version",
+			WriteError::IntegerConstant(..) => "integer constant in a version without integers",
+		}
+	}
+
+	fn cause(&self) -> Option<&error::Error> {
+		match *self {
+			WriteError::Io(ref err) => Some(err),
+			_ => None,
+		}
+	}
+}
+
+impl From<io::Error> for WriteError {
+	fn from(err: io::Error) -> WriteError {
+		WriteError::Io(err)
+	}
+}
+
+impl From<byteorder::Error> for WriteError {
+	fn from(err: byteorder::Error) -> WriteError {
+		WriteError::Io(err.into())
+	}
+}
+
+/// Options controlling how bytecode is written.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct WriteOptions {
+	/// The deepest nesting of functions which is written before failing
+	/// with `TooDeep`, rather than risking overflowing the stack. The main
+	/// function is at depth 0.
+	///
+	/// Defaults to `DEFAULT_MAX_DEPTH`.
+	pub max_depth: usize,
+	/// Write the length of each string marked with
+	/// `LuaString::long_length` in the long form, as it was read with
+	/// `ReadOptions::preserve_encoding`, rather than in the shortest form
+	/// as Lua does.
+	///
+	/// Defaults to `false`.
+	pub preserve_encoding: bool,
+}
+
+/// The default `WriteOptions::max_depth` and `ReadOptions::max_depth`,
+/// matching the limit on nested C calls which bounds how deeply Lua's own
+/// parser can nest functions.
+pub const DEFAULT_MAX_DEPTH: usize = 200;
+
+impl Default for WriteOptions {
+	fn default() -> WriteOptions {
+		WriteOptions {
+			max_depth: DEFAULT_MAX_DEPTH,
+			preserve_encoding: false,
+		}
+	}
+}
+
+/// Serialize a `Function` to bytecode for the host platform.
+pub fn write_file<W: Write>(write: W, function: &Function) -> Result<(), WriteError> {
+	write_with_header(write, &Header::native(), function, &WriteOptions::default())
+}
+
+/// Serialize a `Chunk` to bytecode, using the byte order and sizes of its
+/// header.
+///
+/// The chunk is written in the Lua 5.3 or 5.1 format, as its header's
+/// `version` says. Lua 5.1's header has a byte order field, while 5.3's has
+/// none; instead the test integer and number are written in the header's
+/// byte order, and readers detect it from them.
+///
+/// String lengths are written in their shortest form, as Lua does. Use
+/// `WriteOptions::preserve_encoding` to reproduce a chunk from another tool
+/// which used the long form for a short string byte for byte.
+pub fn write_chunk<W: Write>(write: W, chunk: &Chunk) -> Result<(), WriteError> {
+	write_chunk_with(write, chunk, &WriteOptions::default())
+}
+
+/// Serialize a `Chunk` to bytecode with the given options.
+pub fn write_chunk_with<W: Write>(write: W, chunk: &Chunk, options: &WriteOptions) -> Result<(), WriteError> {
+	write_with_header(write, &chunk.header, &chunk.main, options)
+}
+
+/// Serialize a `Chunk` to bytecode in a new file at `path`, replacing any
+/// existing file.
+pub fn write_chunk_to_path<P: AsRef<Path>>(path: P, chunk: &Chunk) -> Result<(), WriteError> {
+	let mut out = BufWriter::new(try!(File::create(path)));
+	try!(write_chunk(&mut out, chunk));
+	try!(out.flush());
+	Ok(())
+}
+
+fn write_with_header<W: Write>(write: W, header: &Header, function: &Function, options: &WriteOptions)
+	-> Result<(), WriteError>
+{
+	match header.endian {
+		Endian::Little => Writer::<_, LittleEndian>::new(write, *header, *options).write_main(function),
+		Endian::Big => Writer::<_, BigEndian>::new(write, *header, *options).write_main(function),
+	}
+}
+
+/// Serialize a `Function` to bytecode for the host platform in the given
+/// buffer.
+///
+/// Returns the number of bytes written. Fails without writing anything if
+/// the buffer is shorter than `function.serialized_len()`.
+pub fn write_file_to_slice(buf: &mut [u8], function: &Function) -> Result<usize, WriteError> {
+	write_to_slice(buf, &Header::native(), function)
+}
+
+/// Serialize a `Chunk` to bytecode in the given buffer, using the byte
+/// order and sizes of its header.
+///
+/// Returns the number of bytes written. Fails with `BufferTooSmall`
+/// without writing anything if the buffer is shorter than
+/// `chunk.serialized_len()`, so that the caller can allocate once.
+pub fn write_chunk_to_slice(buf: &mut [u8], chunk: &Chunk) -> Result<usize, WriteError> {
+	write_to_slice(buf, &chunk.header, &chunk.main)
+}
+
+fn write_to_slice(buf: &mut [u8], header: &Header, function: &Function) -> Result<usize, WriteError> {
+	let options = WriteOptions::default();
+	let mut counter = Counter(0);
+	try!(write_with_header(&mut counter, header, function, &options));
+	let needed = counter.0;
+	if buf.len() < needed {
+		return Err(WriteError::BufferTooSmall {
+			needed,
+			available: buf.len(),
+		});
+	}
+	try!(write_with_header(&mut buf[..needed], header, function, &options));
+	Ok(needed)
+}
+
+impl Chunk {
+	/// Check that the chunk can be written, without writing anything.
+	///
+	/// This reports any error `write_chunk` would other than I/O errors,
+	/// such as a constant which doesn't fit the header's sizes, so that
+	/// output isn't left partially written when it fails.
+	pub fn check_writable(&self) -> Result<(), WriteError> {
+		write_chunk(io::sink(), self)
+	}
+
+	/// The length of the chunk's serialized bytecode, in bytes.
+	pub fn serialized_len(&self) -> Result<usize, WriteError> {
+		let mut counter = Counter(0);
+		try!(write_chunk(&mut counter, self));
+		Ok(counter.0)
+	}
+
+	/// Serialize the chunk to a new byte vector.
+	pub fn to_bytes(&self) -> Result<Vec<u8>, WriteError> {
+		let mut bytes = Vec::new();
+		try!(write_chunk(&mut bytes, self));
+		Ok(bytes)
+	}
+
+	/// Compute a hash of the chunk's serialized bytecode.
+	///
+	/// The hash is 64-bit FNV-1a over the output of `write_chunk` with the
+	/// chunk's own header, so it is stable across runs and hosts, and equal
+	/// for chunks which serialize equally however they were built.
+	pub fn content_hash(&self) -> Result<u64, WriteError> {
+		let mut hasher = Fnv1a::new();
+		try!(write_chunk(&mut hasher, self));
+		Ok(hasher.0)
+	}
+}
+
+impl Header {
+	/// Check that chunks can be written with this header.
+	///
+	/// Only the Lua 5.3 and 5.1 formats can be written. Each size must be 4
+	/// or 8 bytes, except that `Number` may have any nonzero size; numbers of
+	/// other sizes are only written as the raw bytes of `Constant::RawFloat`.
+	pub fn check_writable(&self) -> Result<(), WriteError> {
+		if self.version == Version::Lua52 {
+			return Err(WriteError::UnsupportedVersion(self.version));
+		}
+		for &(field, size) in &[
+			("sizeof(int)", self.int_size),
+			("sizeof(size_t)", self.size_t_size),
+			("sizeof(Instruction)", self.instruction_size),
+			("sizeof(Integer)", self.integer_size),
+		] {
+			if size != 4 && size != 8 {
+				return Err(WriteError::UnsupportedSize { field, size });
+			}
+		}
+		if self.number_size == 0 {
+			return Err(WriteError::UnsupportedSize { field: "sizeof(Number)", size: 0 });
+		}
+		Ok(())
+	}
+}
+
+impl Function {
+	/// The length of the function's serialized bytecode for the host
+	/// platform, as written by `write_file`, in bytes.
+	pub fn serialized_len(&self) -> Result<usize, WriteError> {
+		let mut counter = Counter(0);
+		try!(write_file(&mut counter, self));
+		Ok(counter.0)
+	}
+
+	/// Compute a hash of the function's serialized bytecode.
+	///
+	/// The function is serialized with `Header::default()` rather than the
+	/// host's header, so the hash is the same on every host. As with
+	/// `Chunk::content_hash`, it is 64-bit FNV-1a and stable across runs.
+	pub fn content_hash(&self) -> Result<u64, WriteError> {
+		let mut hasher = Fnv1a::new();
+		try!(write_with_header(&mut hasher, &Header::default(), self, &WriteOptions::default()));
+		Ok(hasher.0)
+	}
+}
+
+/// A writer which streams a chunk out one function at a time, rather than
+/// requiring the whole chunk to be built first.
+///
+/// Functions are begun and ended in the order they nest, and the code and
+/// constants of the innermost open function may be pushed to as they are
+/// generated. Since the format records the length of each list before its
+/// contents, each function is held in memory until it ends; a nested
+/// function is then serialized into its parent, and the main function to
+/// the output.
+pub struct ChunkWriter<W: Write> {
+	out: W,
+	header: Header,
+	stack: Vec<Frame>,
+	finished: bool,
+}
+
+/// A function being streamed, with its nested functions already serialized.
+struct Frame {
+	function: Function,
+	proto_count: usize,
+	protos: Vec<u8>,
+}
+
+impl<W: Write> ChunkWriter<W> {
+	/// A writer which will write a chunk with the given header to `out`.
+	///
+	/// Nothing is written until the main function ends.
+	pub fn new(out: W, header: Header) -> ChunkWriter<W> {
+		ChunkWriter {
+			out,
+			header,
+			stack: Vec::new(),
+			finished: false,
+		}
+	}
+
+	/// Begin a function, nested in the current one if there is one.
+	///
+	/// `function` provides everything but the code and constants pushed
+	/// before it ends and the nested functions begun within it, which are
+	/// added after any it already has.
+	///
+	/// Panics if the main function has already ended.
+	pub fn begin_function(&mut self, mut function: Function) -> Result<(), WriteError> {
+		assert!(!self.finished, "the main function has already ended");
+		let protos = mem::take(&mut function.protos);
+		self.stack.push(Frame {
+			function,
+			proto_count: 0,
+			protos: Vec::new(),
+		});
+		for proto in protos {
+			try!(self.begin_function(proto));
+			try!(self.end_function());
+		}
+		Ok(())
+	}
+
+	/// The innermost open function, to modify before it ends.
+	///
+	/// Its `protos` are always empty, as nested functions are serialized as
+	/// soon as they end.
+	pub fn function_mut(&mut self) -> Option<&mut Function> {
+		self.stack.last_mut().map(|frame| &mut frame.function)
+	}
+
+	/// Add an instruction to the innermost open function.
+	///
+	/// Panics if no function is open.
+	pub fn push_instruction(&mut self, ins: Instruction) {
+		self.function_mut().expect("no open function").code.push(ins);
+	}
+
+	/// Add a constant to the innermost open function, returning its index.
+	///
+	/// Panics if no function is open.
+	pub fn push_constant(&mut self, constant: Constant) -> usize {
+		let function = self.function_mut().expect("no open function");
+		function.constants.push(constant);
+		function.constants.len() - 1
+	}
+
+	/// End the innermost open function, serializing it into its parent or,
+	/// for the main function, writing the chunk to the output.
+	///
+	/// Panics if no function is open.
+	pub fn end_function(&mut self) -> Result<(), WriteError> {
+		let frame = self.stack.pop().expect("no open function");
+		match self.stack.last_mut() {
+			Some(parent) => {
+				try!(write_frame(&mut parent.protos, &self.header, &frame, false));
+				parent.proto_count += 1;
+			}
+			None => {
+				try!(write_frame(&mut self.out, &self.header, &frame, true));
+				self.finished = true;
+			}
+		}
+		Ok(())
+	}
+
+	/// Return the output once the main function has ended.
+	///
+	/// Panics if the main function has not ended.
+	pub fn finish(self) -> W {
+		assert!(self.finished, "the main function has not ended");
+		self.out
+	}
+}
+
+fn write_frame<W: Write>(write: W, header: &Header, frame: &Frame, main: bool) -> Result<(), WriteError> {
+	match header.endian {
+		Endian::Little => Writer::<_, LittleEndian>::new(write, *header, WriteOptions::default())
+			.write_frame(frame, main),
+		Endian::Big => Writer::<_, BigEndian>::new(write, *header, WriteOptions::default())
+			.write_frame(frame, main),
+	}
+}
+
+/// A writer which computes the 64-bit FNV-1a hash of its input.
+pub struct Fnv1a(pub u64);
+
+impl Fnv1a {
+	pub fn new() -> Fnv1a {
+		Fnv1a(0xcbf29ce484222325)
+	}
+}
+
+impl Default for Fnv1a {
+	fn default() -> Fnv1a {
+		Fnv1a::new()
+	}
+}
+
+impl Write for Fnv1a {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		for &byte in buf {
+			self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100000001b3);
+		}
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+struct Counter(usize);
+
+impl Write for Counter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.0 += buf.len();
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+struct Writer<W: Write, E: ByteOrder> {
 	out: W,
+	header: Header,
+	options: WriteOptions,
+	depth: usize,
+	endian: PhantomData<E>,
 }
 
-impl<W: Write> Writer<W> {
-	fn write_header(&mut self) -> io::Result<()> {
+impl<W: Write, E: ByteOrder> Writer<W, E> {
+	fn new(write: W, header: Header, options: WriteOptions) -> Writer<W, E> {
+		Writer {
+			out: write,
+			header,
+			options,
+			depth: 0,
+			endian: PhantomData,
+		}
+	}
+
+	fn write_main(&mut self, function: &Function) -> Result<(), WriteError> {
+		try!(self.write_prefix(function));
+		self.write_function(function)
+	}
+
+	/// Write what precedes the main function: the header and, in Lua 5.3,
+	/// the main function's upvalue count.
+	fn write_prefix(&mut self, main: &Function) -> Result<(), WriteError> {
+		if self.header.version == Version::Lua51 {
+			return self.write_header_51();
+		}
+		try!(self.write_header());
+		self.write_upvalue_count(main)
+	}
+
+	fn write_upvalue_count(&mut self, function: &Function) -> Result<(), WriteError> {
+		if function.upvalues.len() > u8::MAX as usize {
+			return Err(WriteError::ValueDoesNotFit {
+				field: "upvalue count",
+				value: function.upvalues.len() as u64,
+			});
+		}
+		try!(self.out.write_u8(function.upvalues.len() as u8));
+		Ok(())
+	}
+
+	/// Write a function whose nested functions are already serialized.
+	fn write_frame(&mut self, frame: &Frame, main: bool) -> Result<(), WriteError> {
+		if main {
+			try!(self.write_prefix(&frame.function));
+		}
+		self.write_function_with(&frame.function, |this| {
+			try!(this.write_len("function count", frame.proto_count));
+			try!(this.out.write_all(&frame.protos));
+			Ok(())
+		})
+	}
+
+	fn write_header(&mut self) -> Result<(), WriteError> {
+		try!(self.header.check_writable());
 		try!(self.out.write_all(SIGNATURE));
 		try!(self.out.write_u8(VERSION));
 		try!(self.out.write_u8(FORMAT));
 		try!(self.out.write_all(DATA));
-		try!(self.out.write_u8(size_of::<Int>() as u8));
-		try!(self.out.write_u8(size_of::<Size>() as u8));
-		try!(self.out.write_u8(size_of::<Instruction>() as u8));
-		try!(self.out.write_u8(size_of::<Integer>() as u8));
-		try!(self.out.write_u8(size_of::<Number>() as u8));
-		try!(self.out.write_i64::<E>(TEST_INT));
-		try!(self.out.write_f64::<E>(TEST_NUMBER));
+		try!(self.out.write_u8(self.header.int_size));
+		try!(self.out.write_u8(self.header.size_t_size));
+		try!(self.out.write_u8(self.header.instruction_size));
+		try!(self.out.write_u8(self.header.integer_size));
+		try!(self.out.write_u8(self.header.number_size));
+		try!(self.write_integer(TEST_INT));
+		self.write_test_number()
+	}
+
+	/// Write a Lua 5.1 header, which records the byte order in a field of
+	/// its own rather than with test values, and ends with a flag for
+	/// builds whose `Number` is an integer type, which is always clear.
+	fn write_header_51(&mut self) -> Result<(), WriteError> {
+		try!(self.header.check_writable());
+		try!(self.out.write_all(SIGNATURE));
+		try!(self.out.write_u8(Version::Lua51.number()));
+		try!(self.out.write_u8(FORMAT));
+		try!(self.out.write_u8(match self.header.endian {
+			Endian::Big => 0,
+			Endian::Little => 1,
+		}));
+		try!(self.out.write_u8(self.header.int_size));
+		try!(self.out.write_u8(self.header.size_t_size));
+		try!(self.out.write_u8(self.header.instruction_size));
+		try!(self.out.write_u8(self.header.number_size));
+		try!(self.out.write_u8(0));
 		Ok(())
 	}
 
-	fn write_function(&mut self, function: &Function) -> io::Result<()> {
-		try!(self.write_string(&function.source));
-		try!(self.out.write_i32::<E>(function.line_start));
-		try!(self.out.write_i32::<E>(function.line_end));
+	/// Write the test number in the header's `Number` size.
+	///
+	/// A `Number` of a size other than 4 or 8 bytes, such as a `long
+	/// double`, has no portable encoding, so its test number is written as
+	/// zeros. The reader doesn't check the test number for those sizes.
+	fn write_test_number(&mut self) -> Result<(), WriteError> {
+		match self.header.number_size {
+			4 | 8 => self.write_number(TEST_NUMBER),
+			size => {
+				try!(self.out.write_all(&vec![0; size as usize]));
+				Ok(())
+			}
+		}
+	}
+
+	fn write_function(&mut self, function: &Function) -> Result<(), WriteError> {
+		if self.depth > self.options.max_depth {
+			return Err(WriteError::TooDeep(self.options.max_depth));
+		}
+		self.depth += 1;
+		let result = self.write_function_with(function, |this| {
+			this.write_vec("function count", &function.protos, |this, proto| this.write_function(proto))
+		});
+		self.depth -= 1;
+		result
+	}
+
+	/// Write a function, calling `protos` to write its nested functions.
+	///
+	/// In Lua 5.1's layout, the upvalue count follows the lines, and the
+	/// upvalue descriptors are not written, since the pseudo-instructions
+	/// following each `CLOSURE` in the parent's code describe them instead.
+	fn write_function_with<F>(&mut self, function: &Function, protos: F) -> Result<(), WriteError>
+		where F: FnOnce(&mut Self) -> Result<(), WriteError>
+	{
+		let lua51 = self.header.version == Version::Lua51;
+		try!(self.write_string_opt(function.source.as_ref()));
+		try!(self.write_int(function.line_start));
+		try!(self.write_int(function.line_end));
+		if lua51 {
+			try!(self.write_upvalue_count(function));
+		}
 		try!(self.out.write_u8(function.num_params));
-		try!(self.out.write_u8(if function.is_vararg { 1 } else { 0 }));
+		try!(self.out.write_u8(function.is_vararg));
 		try!(self.out.write_u8(function.max_stack_size));
 
-		try!(self.out.write_u32::<E>(function.code.len() as u32));
-		for &ins in &function.code {
-			try!(self.out.write_u32::<E>(ins));
-		}
-		try!(self.out.write_u32::<E>(function.constants.len() as u32));
-		for cons in &function.constants {
-			match cons {
-				&Constant::Nil => try!(self.out.write_u8(0x00)),
-				&Constant::Boolean(b) => try!(self.out.write_all(&[0x01, if b { 1 } else { 0 }])),
-				&Constant::Float(n) => {
-					try!(self.out.write_u8(0x03));
-					try!(self.out.write_f64::<E>(n));
-				}
-				&Constant::Int(n) => {
-					try!(self.out.write_u8(0x13));
-					try!(self.out.write_i64::<E>(n));
+		try!(self.write_vec("code length", &function.code, |this, &ins| this.write_instruction(ins)));
+		try!(self.write_vec("constant count", &function.constants, |this, cons| {
+			if let &Constant::RawFloat(ref bytes) = cons {
+				if bytes.len() != this.header.number_size as usize {
+					return Err(WriteError::ValueDoesNotFit {
+						field: "raw number length",
+						value: bytes.len() as u64,
+					});
 				}
-				&Constant::ShortString(ref s) => {
-					try!(self.out.write_u8(0x04));
-					try!(self.write_string(s));
-				}
-				&Constant::LongString(ref s) => {
-					try!(self.out.write_u8(0x14));
-					try!(self.write_string(s));
+			}
+			if let &Constant::Int(value) = cons {
+				if !this.header.version.has_integers() {
+					return Err(WriteError::IntegerConstant(value));
 				}
 			}
+			let tag = cons.type_tag(this.header.version).expect("only integers lack a tag in some versions");
+			try!(this.out.write_u8(tag));
+			match cons {
+				&Constant::Nil => {}
+				&Constant::Boolean(b) => try!(this.out.write_u8(if b { 1 } else { 0 })),
+				&Constant::Float(n) => try!(this.write_number(n)),
+				&Constant::RawFloat(ref bytes) => try!(this.out.write_all(bytes)),
+				&Constant::Int(n) => try!(this.write_integer(n)),
+				&Constant::ShortString(ref s) | &Constant::LongString(ref s) => try!(this.write_string(s)),
+			}
+			Ok(())
+		}));
+		if !lua51 {
+			try!(self.write_vec("upvalue count", &function.upvalues, |this, upval| {
+				try!(match upval {
+					&Upvalue::Outer(idx) => this.out.write_all(&[0, idx]),
+					&Upvalue::Stack(idx) => this.out.write_all(&[1, idx]),
+				});
+				Ok(())
+			}));
 		}
-		try!(self.out.write_u32::<E>(function.upvalues.len() as u32));
-		for upval in &function.upvalues {
-			try!(match upval {
-				&Upvalue::Outer(idx) => self.out.write_all(&[0, idx]),
-				&Upvalue::Stack(idx) => self.out.write_all(&[1, idx]),
-			});
-		}
-		try!(self.out.write_u32::<E>(function.protos.len() as u32));
-		for proto in &function.protos {
-			try!(self.write_function(proto));
-		}
+		// Nested functions come before this function's debug info.
+		try!(protos(self));
 		// debug
-		try!(self.out.write_u32::<E>(function.debug.lineinfo.len() as u32));
-		for &line in &function.debug.lineinfo {
-			try!(self.out.write_i32::<E>(line));
+		try!(self.write_vec("line info count", &function.debug.lineinfo, |this, &line| this.write_int(line)));
+		try!(self.write_vec("local variable count", &function.debug.localvars, |this, var| {
+			try!(this.write_string(&var.name));
+			try!(this.write_int(var.start_pc));
+			this.write_int(var.end_pc)
+		}));
+		self.write_vec("upvalue name count", &function.debug.upvalues, |this, upval| this.write_string(upval))
+	}
+
+	#[inline]
+	fn write_vec<F, T>(&mut self, field: &'static str, items: &[T], f: F) -> Result<(), WriteError>
+		where F: Fn(&mut Self, &T) -> Result<(), WriteError>
+	{
+		try!(self.write_len(field, items.len()));
+		for item in items {
+			try!(f(self, item));
 		}
-		try!(self.out.write_u32::<E>(function.debug.localvars.len() as u32));
-		for var in &function.debug.localvars {
-			try!(self.write_string(&var.name));
-			try!(self.out.write_i32::<E>(var.start_pc));
-			try!(self.out.write_i32::<E>(var.end_pc));
+		Ok(())
+	}
+
+	fn write_len(&mut self, field: &'static str, len: usize) -> Result<(), WriteError> {
+		if len > Int::MAX as usize {
+			return Err(WriteError::ValueDoesNotFit {
+				field,
+				value: len as u64,
+			});
 		}
-		try!(self.out.write_u32::<E>(function.debug.upvalues.len() as u32));
-		for upval in &function.debug.upvalues {
-			try!(self.write_string(upval));
+		self.write_int(len as Int)
+	}
+
+	fn write_int(&mut self, value: Int) -> Result<(), WriteError> {
+		try!(match self.header.int_size {
+			4 => self.out.write_i32::<E>(value),
+			_ => self.out.write_i64::<E>(value as i64),
+		});
+		Ok(())
+	}
+
+	fn write_size(&mut self, value: usize) -> Result<(), WriteError> {
+		try!(match self.header.size_t_size {
+			4 => self.out.write_u32::<E>(value as u32),
+			_ => self.out.write_u64::<E>(value as u64),
+		});
+		Ok(())
+	}
+
+	fn write_instruction(&mut self, value: Instruction) -> Result<(), WriteError> {
+		try!(match self.header.instruction_size {
+			4 => self.out.write_u32::<E>(value),
+			_ => self.out.write_u64::<E>(value as u64),
+		});
+		Ok(())
+	}
+
+	fn write_integer(&mut self, value: Integer) -> Result<(), WriteError> {
+		match self.header.integer_size {
+			4 if value as i32 as Integer != value => return Err(WriteError::ValueDoesNotFit {
+				field: "integer",
+				value: value as u64,
+			}),
+			4 => try!(self.out.write_i32::<E>(value as i32)),
+			_ => try!(self.out.write_i64::<E>(value)),
 		}
 		Ok(())
 	}
 
-	fn write_string(&mut self, string: &str) -> io::Result<()> {
-		if string.len() >= 0xff {
+	fn write_number(&mut self, value: Number) -> Result<(), WriteError> {
+		try!(match self.header.number_size {
+			4 => self.out.write_f32::<E>(value as f32),
+			8 => self.out.write_f64::<E>(value),
+			// Only `RawFloat` can be written in other sizes.
+			_ => return Err(WriteError::ValueDoesNotFit {
+				field: "float",
+				value: value.to_bits(),
+			}),
+		});
+		Ok(())
+	}
+
+	fn write_string(&mut self, string: &LuaString) -> Result<(), WriteError> {
+		self.write_string_opt(Some(string))
+	}
+
+	fn write_string_opt(&mut self, string: Option<&LuaString>) -> Result<(), WriteError> {
+		if self.header.version == Version::Lua51 {
+			return self.write_string_opt_51(string);
+		}
+		let string = match string {
+			Some(s) => s,
+			None => {
+				try!(self.out.write_all(&[0]));
+				return Ok(());
+			}
+		};
+		// The length is written plus one, to leave zero for no string, and
+		// a byte of 0xff marks a length too long to fit in that byte. The
+		// long form is only used when needed, matching Lua's own output,
+		// unless the string was read in it and that is to be preserved.
+		let size = string.len() + 1;
+		let long = self.options.preserve_encoding && string.long_length();
+		if self.header.size_t_size == 4 && size > u32::MAX as usize {
+			return Err(WriteError::StringTooLong(string.len()));
+		} else if size >= 0xff || long {
 			try!(self.out.write_u8(0xff));
-			try!(self.out.write_u32::<E>(string.len() as u32 + 1));
+			try!(self.write_size(size));
 		} else {
-			try!(self.out.write_u8(string.len() as u8 + 1));
+			try!(self.out.write_u8(size as u8));
 		}
-		self.out.write_all(string.as_bytes())
+		try!(self.out.write_all(string));
+		Ok(())
+	}
+
+	/// Write a string in Lua 5.1's form: its length plus one as a `size_t`,
+	/// or zero for no string, and then its bytes followed by a NUL.
+	fn write_string_opt_51(&mut self, string: Option<&LuaString>) -> Result<(), WriteError> {
+		let string = match string {
+			Some(s) => s,
+			None => return self.write_size(0),
+		};
+		let size = string.len() + 1;
+		if self.header.size_t_size == 4 && size > u32::MAX as usize {
+			return Err(WriteError::StringTooLong(string.len()));
+		}
+		try!(self.write_size(size));
+		try!(self.out.write_all(string));
+		try!(self.out.write_u8(0));
+		Ok(())
 	}
 }
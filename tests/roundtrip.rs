@@ -0,0 +1,64 @@
+//! Runs the `roundtrip` example on a chunk written to a temporary file.
+
+extern crate lua_kit;
+
+use std::{env, fs, process};
+use std::path::PathBuf;
+
+use lua_kit::{Chunk, Debug, Function, LocalVar};
+
+/// The path of an example, which Cargo builds alongside the tests.
+fn example(name: &str) -> PathBuf {
+	let mut path = env::current_exe().unwrap();
+	path.pop();
+	if path.ends_with("deps") {
+		path.pop();
+	}
+	path.push("examples");
+	path.push(name);
+	path.set_extension(env::consts::EXE_EXTENSION);
+	path
+}
+
+/// A path in the temporary directory unique to this process.
+fn temp_path(name: &str) -> PathBuf {
+	env::temp_dir().join(format!("lua-kit-{}-{}", process::id(), name))
+}
+
+#[test]
+fn strips_debug_info() {
+	let mut main = Function::empty_main();
+	main.source = Some("@fixture.lua".into());
+	main.debug = Debug {
+		lineinfo: vec![1],
+		localvars: vec![LocalVar { name: "x".into(), start_pc: 0, end_pc: 1 }],
+		upvalues: vec!["_ENV".into()],
+	};
+	let input = temp_path("input.luac");
+	let output = temp_path("output.luac");
+	lua_kit::write_chunk_to_path(&input, &Chunk::new(main.clone())).unwrap();
+
+	let result = process::Command::new(example("roundtrip")).arg(&input).arg(&output).output().unwrap();
+	assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+	let chunk = lua_kit::read_chunk_from_path(&output).unwrap();
+	main.strip_debug();
+	assert_eq!(chunk.main, main);
+
+	fs::remove_file(&input).unwrap();
+	fs::remove_file(&output).unwrap();
+}
+
+#[test]
+fn reports_errors() {
+	let input = temp_path("missing.luac");
+	let output = temp_path("unwritten.luac");
+	let result = process::Command::new(example("roundtrip")).arg(&input).arg(&output).output().unwrap();
+	assert!(!result.status.success());
+	assert!(result.stdout.is_empty());
+	assert!(String::from_utf8_lossy(&result.stderr).starts_with(&*input.to_string_lossy()));
+	assert!(!output.exists());
+
+	let result = process::Command::new(example("roundtrip")).output().unwrap();
+	assert!(!result.status.success());
+	assert_eq!(String::from_utf8_lossy(&result.stderr), "usage: roundtrip <input> <output>\n");
+}